@@ -44,7 +44,7 @@ def main():
         test = gen_items(NT, K, S)
 
     with timed(f"creating ItemsSet"):
-        iset = binpack_pyo3.ItemSets(base)
+        iset = binpack_pyo3.ItemSets(np.array(base, dtype=np.uint8))
         print(f"  stored item sets have on average {np.mean(np.sum(base, axis=1))} items")
         print(f"  using estimated {iset.memory_used()} bytes for {NB} items of len {K}")
 
@@ -79,6 +79,23 @@ def main():
     tst("given_fits_into_any(par=True)", fname="given_fits_into_any", par=True)
     tst("given_fits_into_all(par=True)", fname="given_fits_into_all", par=True)
 
+    test_array = np.array(test, dtype=np.uint8)
+
+    def tst_each(msg, fname="any_fit_into_each", **kwargs):
+        with timed(f"{msg}", units=NT*NB*Rep):
+            for _i in range(Rep):
+                tot = sum(int(r) for r in getattr(iset, fname)(test_array, **kwargs))
+            if "how_many" in msg:
+                print(f"  total matching pairs found in item sets: {tot} out of {NT*NB}")
+            else:
+                print(f"  matching test item sets: {tot} out of {NT}")
+
+    print("\n## Batch queries of all the test item sets at once")
+
+    tst_each("any_fit_into_each(par=True)", par=True)
+    tst_each("any_fit_into_each(branchings=10, par=True)", branching=10, par=True)
+    tst_each("each_fits_into_how_many(branchings=100, par=True)", fname="each_fits_into_how_many", branching=100, par=True)
+
     print("\n## Counting all matching pairs")
 
     tst("given_fits_into_how_many", fname="given_fits_into_how_many")
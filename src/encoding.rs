@@ -0,0 +1,122 @@
+use crate::packing_common::{Count, RangeError};
+use crate::storage::{SetBuf, Storage};
+
+/// Compact binary encoding of item sets (used for pickling).
+///
+/// Every item set is encoded as varints (LEB128): the length of its count vector,
+/// the number `k` of non-zero counts, then `k` pairs of (size difference to the previous
+/// non-zero size, count), sizes increasing. Item sets with few distinct sizes thus take
+/// a few bytes each, regardless of the storage mode and the count type.
+pub fn encode_sets<T: Count>(sets: &Storage<T>) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, sets.len() as u64);
+    for s in sets.iter() {
+        let nonzero = s.nonzero();
+        write_varint(&mut out, s.dense_len() as u64);
+        write_varint(&mut out, nonzero.len() as u64);
+        let mut prev = 0;
+        for (size, c) in nonzero {
+            write_varint(&mut out, (size - prev) as u64);
+            write_varint(&mut out, c.as_usize() as u64);
+            prev = size;
+        }
+    }
+    out
+}
+
+/// Decode item sets encoded with `encode_sets` into a new storage,
+/// checking that they are valid with the count type and `max_size`
+pub fn decode_sets<T: Count>(
+    data: &[u8],
+    sparse: bool,
+    max_size: usize,
+) -> Result<Storage<T>, String> {
+    let mut pos = 0;
+    let mut sets = Storage::new(sparse);
+    let n = read_varint(data, &mut pos)?;
+    for i in 0..n {
+        let len = read_varint(data, &mut pos)? as usize;
+        // Checked before anything of `len` is allocated
+        if len > max_size.saturating_add(1) {
+            return Err(format!(
+                "Item set {}: length {} is over the maximum size {} + 1",
+                i, len, max_size
+            ));
+        }
+        let k = read_varint(data, &mut pos)?;
+        if k > len as u64 {
+            return Err(format!(
+                "Item set {}: {} counts in a vector of length {}",
+                i, k, len
+            ));
+        }
+        let mut nonzero = Vec::new();
+        let mut size = 0usize;
+        for _ in 0..k {
+            let gap = read_varint(data, &mut pos)? as usize;
+            size = size.saturating_add(gap);
+            if gap == 0 || size >= len {
+                return Err(format!("Item set {}: invalid size {}", i, size));
+            }
+            if size > max_size || (sparse && size > u32::MAX as usize) {
+                let e = RangeError::SizeTooLarge {
+                    index: size,
+                    size,
+                    max_size: max_size.min(if sparse {
+                        u32::MAX as usize
+                    } else {
+                        usize::MAX
+                    }),
+                };
+                return Err(format!("Item set {}: {}", i, e));
+            }
+            let c = read_varint(data, &mut pos)? as usize;
+            match T::try_from(c) {
+                Ok(c) if c != T::default() => nonzero.push((size, c)),
+                _ => {
+                    return Err(format!(
+                        "Item set {}: invalid count {} of size {}",
+                        i, c, size
+                    ))
+                }
+            }
+        }
+        if sparse {
+            sets.push(SetBuf::Sparse(
+                nonzero.into_iter().map(|(s, c)| (s as u32, c)).collect(),
+            ));
+        } else {
+            let mut cs = vec![T::default(); len];
+            for (s, c) in nonzero {
+                cs[s] = c;
+            }
+            sets.push(SetBuf::Dense(cs));
+        }
+    }
+    if pos != data.len() {
+        return Err(format!("{} trailing bytes", data.len() - pos));
+    }
+    sets.shrink_to_fit();
+    Ok(sets)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *data.get(*pos).ok_or("Truncated data")?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err("Invalid varint".into())
+}
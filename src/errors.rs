@@ -0,0 +1,175 @@
+use crate::packing_common::RangeError;
+use crate::storage::StoreError;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIndexError, PyOverflowError, PyTypeError, PyValueError};
+use pyo3::once_cell::GILOnceCell;
+use pyo3::prelude::*;
+use pyo3::type_object::PyTypeObject;
+use pyo3::types::{PyDict, PySequence, PyTuple, PyType};
+use std::io;
+use std::path::Path;
+
+create_exception!(
+    binpack_pyo3,
+    BinpackError,
+    PyException,
+    "Base class of all the errors raised by binpack_pyo3"
+);
+
+static VALUE_ERROR: GILOnceCell<Py<PyType>> = GILOnceCell::new();
+static OVERFLOW_ERROR: GILOnceCell<Py<PyType>> = GILOnceCell::new();
+
+/// Create a subclass of both `BinpackError` and the builtin `base`
+/// (`create_exception!` only supports a single base class)
+fn subclass(py: Python, name: &str, base: &PyType, doc: &str) -> Py<PyType> {
+    let bases = PyTuple::new(py, [BinpackError::type_object(py), base]);
+    let dict = PyDict::new(py);
+    dict.set_item("__module__", "binpack_pyo3").unwrap();
+    dict.set_item("__doc__", doc).unwrap();
+    py.get_type::<PyType>()
+        .call1((name, bases, dict))
+        .and_then(|t| t.extract())
+        .expect("Failed to create an exception type")
+}
+
+/// `binpack_pyo3.BinpackValueError`, subclass of `BinpackError` and `ValueError`
+pub fn value_error_type(py: Python<'_>) -> &PyType {
+    VALUE_ERROR
+        .get_or_init(py, || {
+            subclass(
+                py,
+                "BinpackValueError",
+                PyValueError::type_object(py),
+                "Invalid item sets, e.g. items of size 0",
+            )
+        })
+        .as_ref(py)
+}
+
+/// `binpack_pyo3.BinpackOverflowError`, subclass of `BinpackError` and `OverflowError`
+pub fn overflow_error_type(py: Python<'_>) -> &PyType {
+    OVERFLOW_ERROR
+        .get_or_init(py, || {
+            subclass(
+                py,
+                "BinpackOverflowError",
+                PyOverflowError::type_object(py),
+                "Item sizes or counts over the limits of the item set storage",
+            )
+        })
+        .as_ref(py)
+}
+
+pub fn value_error(msg: String) -> PyErr {
+    Python::with_gil(|py| PyErr::from_type(value_error_type(py), msg))
+}
+
+pub fn overflow_error(msg: String) -> PyErr {
+    Python::with_gil(|py| PyErr::from_type(overflow_error_type(py), msg))
+}
+
+pub fn range_error(e: RangeError) -> PyErr {
+    if e.is_overflow() {
+        overflow_error(e.to_string())
+    } else {
+        value_error(e.to_string())
+    }
+}
+
+/// Modifying mapped item sets raises `TypeError`, invalid item sets as in `range_error`
+pub fn store_error(e: StoreError) -> PyErr {
+    match e {
+        StoreError::Range(e) => range_error(e),
+        StoreError::ReadOnly => PyTypeError::new_err(e.to_string()),
+    }
+}
+
+/// Same as `range_error`, naming the item set with the error
+pub fn range_error_in(set: usize, e: RangeError) -> PyErr {
+    let msg = format!("Item set {}: {}", set, e);
+    if e.is_overflow() {
+        overflow_error(msg)
+    } else {
+        value_error(msg)
+    }
+}
+
+/// Same as `store_error`, naming the item set with an invalid item set error
+pub fn store_error_in(set: usize, e: StoreError) -> PyErr {
+    match e {
+        StoreError::Range(e) => range_error_in(set, e),
+        e => store_error(e),
+    }
+}
+
+pub fn index_error(idx: impl std::fmt::Display, len: usize) -> PyErr {
+    PyIndexError::new_err(format!("Index {} out of bounds for {} item sets", idx, len))
+}
+
+/// Invalid file contents raise `BinpackValueError` naming the file, item sets that cannot be
+/// saved as in `range_error`, other I/O errors `OSError`
+pub fn io_error(path: &Path, e: io::Error) -> PyErr {
+    if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<RangeError>()) {
+        range_error(e.clone())
+    } else if e.kind() == io::ErrorKind::InvalidData {
+        value_error(format!("{}: {}", path.display(), e))
+    } else {
+        e.into()
+    }
+}
+
+/// Extract a sequence of non-negative integers (sizes or counts),
+/// naming the index of the first invalid value
+pub fn extract_usizes(obj: &PyAny) -> PyResult<Vec<usize>> {
+    let py = obj.py();
+    obj.iter()?
+        .enumerate()
+        .map(|(i, x)| {
+            let x = x?;
+            x.extract::<usize>().map_err(|e| {
+                if !e.is_instance_of::<PyOverflowError>(py) {
+                    PyTypeError::new_err(format!("Invalid value at index {}: {}", i, e.value(py)))
+                } else if x.extract::<i64>().is_ok_and(|v| v < 0) {
+                    value_error(format!("Negative value {} at index {}", x, i))
+                } else {
+                    overflow_error(format!("Value {} at index {} is too large", x, i))
+                }
+            })
+        })
+        .collect()
+}
+
+/// Extract `(size, count)` pairs, given as a dict `{size: count}` or a sequence of tuples
+/// (`None` for other objects, such as count vectors)
+pub fn extract_pairs(obj: &PyAny) -> PyResult<Option<Vec<(usize, usize)>>> {
+    let items: &PyAny = if let Ok(d) = obj.downcast::<PyDict>() {
+        d.items()
+    } else if obj
+        .downcast::<PySequence>()
+        .is_ok_and(|s| s.get_item(0).is_ok_and(|x| x.downcast::<PyTuple>().is_ok()))
+    {
+        obj
+    } else {
+        return Ok(None);
+    };
+    items
+        .iter()?
+        .enumerate()
+        .map(|(i, pair)| match extract_usizes(pair?)?[..] {
+            [size, count] => Ok((size, count)),
+            _ => Err(value_error(format!(
+                "Invalid (size, count) pair at index {}",
+                i
+            ))),
+        })
+        .collect::<PyResult<_>>()
+        .map(Some)
+}
+
+/// Register the exception types in the module
+pub fn add_exceptions(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("BinpackError", BinpackError::type_object(py))?;
+    m.add("BinpackValueError", value_error_type(py))?;
+    m.add("BinpackOverflowError", overflow_error_type(py))?;
+    Ok(())
+}
@@ -0,0 +1,303 @@
+//! Binary file format of item sets, version 1.
+//!
+//! All the numbers are little-endian. The file consists of:
+//!
+//! ```text
+//! offset  size  field
+//!      0     8  magic "BINPACK\0"
+//!      8     4  format version (u32, currently 1)
+//!     12     4  element type C: size of the counts in bytes (u32, 1, 2 or 4)
+//!     16     8  max_size (u64)
+//!     24     8  number of item sets n (u64)
+//!     32     8  total length of the count vectors in elements (u64)
+//!     40     4  CRC-32 of the offsets (u32)
+//!     44     4  CRC-32 of the counts (u32)
+//!     48    12  reserved, zero
+//!     60     4  CRC-32 of the header bytes 0..60 (u32)
+//!     64  8n+8  offsets of the count vectors in elements (n+1 times u64, from 0 to the total length),
+//!               vector `i` has length `offsets[i+1] - offsets[i]`
+//!      .     .  counts: all the count vectors concatenated (elements of type C)
+//! ```
+//!
+//! The counts start at a multiple of 8 bytes, so the file can also be used memory-mapped
+//! (see `MappedSets`). On load, the header, checksums, offsets and all the count vectors are validated.
+
+use crate::packing_common::{check_counts, Count, RangeError};
+use crate::storage::{SetRef, Storage};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const MAGIC: &[u8; 8] = b"BINPACK\0";
+pub const VERSION: u32 = 1;
+pub const HEADER_LEN: usize = 64;
+
+/// The parsed header of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub elem_bytes: usize,
+    pub max_size: usize,
+    pub n_sets: usize,
+    pub data_len: usize,
+    pub offsets_crc: u32,
+    pub data_crc: u32,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut b = Vec::with_capacity(HEADER_LEN);
+        b.extend_from_slice(MAGIC);
+        b.extend_from_slice(&VERSION.to_le_bytes());
+        b.extend_from_slice(&(self.elem_bytes as u32).to_le_bytes());
+        b.extend_from_slice(&(self.max_size as u64).to_le_bytes());
+        b.extend_from_slice(&(self.n_sets as u64).to_le_bytes());
+        b.extend_from_slice(&(self.data_len as u64).to_le_bytes());
+        b.extend_from_slice(&self.offsets_crc.to_le_bytes());
+        b.extend_from_slice(&self.data_crc.to_le_bytes());
+        b.resize(HEADER_LEN - 4, 0);
+        let crc = crc32fast::hash(&b);
+        b.extend_from_slice(&crc.to_le_bytes());
+        b.try_into().unwrap()
+    }
+
+    /// Parse and validate the header, checking the element type against `T`
+    pub fn parse<T: Count>(data: &[u8]) -> Result<Header, String> {
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return Err("Not an item sets file".into());
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        if crc32fast::hash(&data[..HEADER_LEN - 4]) != u32_at(HEADER_LEN - 4) {
+            return Err("Header checksum mismatch".into());
+        }
+        if u32_at(8) != VERSION {
+            return Err(format!("Unsupported file format version {}", u32_at(8)));
+        }
+        let h = Header {
+            elem_bytes: u32_at(12) as usize,
+            max_size: usize::try_from(u64_at(16)).map_err(|_| "Invalid max_size")?,
+            n_sets: usize::try_from(u64_at(24)).map_err(|_| "Invalid number of item sets")?,
+            data_len: usize::try_from(u64_at(32)).map_err(|_| "Invalid data length")?,
+            offsets_crc: u32_at(40),
+            data_crc: u32_at(44),
+        };
+        // Bounds the sizes of the sections (and their computation)
+        if h.n_sets >= data.len() / 8 || h.data_len > data.len() {
+            return Err("Truncated file".into());
+        }
+        if h.elem_bytes != T::BYTES {
+            return Err(format!(
+                "File has counts of {} bytes, expected {} bytes",
+                h.elem_bytes,
+                T::BYTES
+            ));
+        }
+        Ok(h)
+    }
+
+    /// Byte ranges of the offsets and the counts
+    pub fn sections(&self) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let offsets_end = HEADER_LEN + 8 * (self.n_sets + 1);
+        (
+            HEADER_LEN..offsets_end,
+            offsets_end..offsets_end + self.data_len * self.elem_bytes,
+        )
+    }
+}
+
+/// Write the item sets (as count vectors) in the file format
+pub fn write_sets<T: Count, W: Write>(
+    w: &mut W,
+    sets: &Storage<T>,
+    max_size: usize,
+) -> io::Result<()> {
+    sets.check_dense_len()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut offsets = Vec::with_capacity(8 * (sets.len() + 1));
+    let mut data_crc = crc32fast::Hasher::new();
+    let mut total = 0usize;
+    let (mut row, mut buf) = (Vec::new(), Vec::new());
+    offsets.extend_from_slice(&0u64.to_le_bytes());
+    for s in sets.iter() {
+        write_dense_le(s, &mut row, &mut buf);
+        data_crc.update(&buf);
+        total += s.dense_len();
+        offsets.extend_from_slice(&(total as u64).to_le_bytes());
+    }
+    let header = Header {
+        elem_bytes: T::BYTES,
+        max_size,
+        n_sets: sets.len(),
+        data_len: total,
+        offsets_crc: crc32fast::hash(&offsets),
+        data_crc: data_crc.finalize(),
+    };
+    w.write_all(&header.to_bytes())?;
+    w.write_all(&offsets)?;
+    for s in sets.iter() {
+        write_dense_le(s, &mut row, &mut buf);
+        w.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+/// Write the counts of `set` into `buf` (cleared), using `row` for the count vector of a sparse set
+fn write_dense_le<T: Count>(set: SetRef<T>, row: &mut Vec<T>, buf: &mut Vec<u8>) {
+    buf.clear();
+    let counts = match set {
+        SetRef::Dense(s) => s,
+        SetRef::Sparse(_) => {
+            row.clear();
+            row.resize(set.dense_len(), T::default());
+            set.write_dense(row);
+            row
+        }
+    };
+    counts.iter().for_each(|c| c.write_le(buf));
+}
+
+/// Validate the file contents and return the header. With `verify`, also checks the checksums
+/// and all the count vectors, otherwise only the header, the offsets and the counts of size 0.
+pub fn validate<T: Count>(data: &[u8], verify: bool) -> Result<Header, String> {
+    let h = Header::parse::<T>(data)?;
+    let (offs, counts) = h.sections();
+    if data.len() != counts.end {
+        return Err(format!(
+            "File has {} bytes, expected {} bytes",
+            data.len(),
+            counts.end
+        ));
+    }
+    if verify && crc32fast::hash(&data[offs]) != h.offsets_crc {
+        return Err("Offsets checksum mismatch".into());
+    }
+    if verify && crc32fast::hash(&data[counts.clone()]) != h.data_crc {
+        return Err("Counts checksum mismatch".into());
+    }
+    let mut prev = 0;
+    for i in 0..=h.n_sets {
+        let o = u64::from_le_bytes(data[offset_pos(i)..offset_pos(i + 1)].try_into().unwrap());
+        match usize::try_from(o) {
+            Ok(o) if o >= prev && o <= h.data_len && (i > 0 || o == 0) => prev = o,
+            _ => return Err("Invalid offsets".into()),
+        }
+    }
+    if prev != h.data_len {
+        return Err("Invalid offsets".into());
+    }
+    if verify {
+        let counts = &data[counts];
+        for i in 0..h.n_sets {
+            let cs = read_counts::<T>(counts, offset(data, i), offset(data, i + 1));
+            check_counts(&cs, h.max_size).map_err(|e| format!("Item set {}: {}", i, e))?;
+        }
+    } else {
+        // The algorithms rely on `counts[0] == 0`, so this is checked even without `verify`
+        let counts = &data[counts];
+        for i in 0..h.n_sets {
+            let (start, end) = (offset(data, i), offset(data, i + 1));
+            if start < end {
+                let count = read_counts::<T>(counts, start, start + 1)[0];
+                if count != T::default() {
+                    let e = RangeError::ZeroSizeCount {
+                        count: count.as_usize(),
+                    };
+                    return Err(format!("Item set {}: {}", i, e));
+                }
+            }
+        }
+    }
+    Ok(h)
+}
+
+/// Position of the `i`-th offset in the file
+fn offset_pos(i: usize) -> usize {
+    HEADER_LEN + 8 * i
+}
+
+/// The `i`-th offset, in a validated file
+pub fn offset(data: &[u8], i: usize) -> usize {
+    u64::from_le_bytes(data[offset_pos(i)..offset_pos(i + 1)].try_into().unwrap()) as usize
+}
+
+/// Read the count vector with elements `start..end` from the counts section
+pub fn read_counts<T: Count>(data: &[u8], start: usize, end: usize) -> Vec<T> {
+    data[start * T::BYTES..end * T::BYTES]
+        .chunks_exact(T::BYTES)
+        .map(T::read_le)
+        .collect()
+}
+
+/// Read and validate item sets in the file format, returns them with the `max_size`
+pub fn read_sets<T: Count>(data: &[u8], sparse: bool) -> Result<(Storage<T>, usize), String> {
+    let h = validate::<T>(data, true)?;
+    if sparse && h.max_size > u32::MAX as usize {
+        return Err(format!("Sparse item sets support sizes up to {}", u32::MAX));
+    }
+    let counts = &data[h.sections().1];
+    let mut sets = Storage::new(sparse);
+    for i in 0..h.n_sets {
+        let cs = read_counts::<T>(counts, offset(data, i), offset(data, i + 1));
+        sets.push(sets.prepare(cs));
+    }
+    sets.shrink_to_fit();
+    Ok((sets, h.max_size))
+}
+
+/// Map the file read-only
+pub fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: the map is read-only, but the file must not be modified while mapped
+    // (which is documented in `open`, as with any memory-mapped file)
+    unsafe { Mmap::map(&file) }
+}
+
+/// Read-only item sets in a memory-mapped file, the count vectors are used in place
+/// (paged in by the OS on access)
+#[derive(Debug, Clone)]
+pub struct MappedSets<T> {
+    mmap: Arc<Mmap>,
+    n_sets: usize,
+    counts: Range<usize>,
+    _counts: PhantomData<T>,
+}
+
+impl<T: Count> MappedSets<T> {
+    /// Validate the mapped file (see `validate`) and return the item sets and `max_size`
+    pub fn new(mmap: Mmap, verify: bool) -> Result<(Self, usize), String> {
+        if cfg!(target_endian = "big") {
+            return Err("Memory-mapped item sets require a little-endian platform".into());
+        }
+        let h = validate::<T>(&mmap, verify)?;
+        let sets = MappedSets {
+            n_sets: h.n_sets,
+            counts: h.sections().1,
+            mmap: Arc::new(mmap),
+            _counts: PhantomData,
+        };
+        Ok((sets, h.max_size))
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_sets
+    }
+
+    /// All the count vectors concatenated
+    fn counts(&self) -> &[T] {
+        let bytes = &self.mmap[self.counts.clone()];
+        // SAFETY: the count types are plain little-endian integers (as checked in `new`),
+        // valid for any bytes; the section starts at a multiple of 8 bytes of the page-aligned map
+        let (pre, counts, post) = unsafe { bytes.align_to::<T>() };
+        assert!(pre.is_empty() && post.is_empty());
+        counts
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&[T]> {
+        (idx < self.n_sets)
+            .then(|| &self.counts()[offset(&self.mmap, idx)..offset(&self.mmap, idx + 1)])
+    }
+}
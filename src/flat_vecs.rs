@@ -0,0 +1,96 @@
+/// Many short vectors stored concatenated in a single buffer, with a table of offsets.
+///
+/// Compared to `Vec<Vec<V>>`, this saves the vector header and the allocation of every vector
+/// and keeps the vectors contiguous in memory for scans.
+#[derive(Debug, Clone)]
+pub struct FlatVecs<V> {
+    data: Vec<V>,
+    /// Vector `i` is `data[offsets[i]..offsets[i + 1]]`, `offsets[0] == 0`
+    offsets: Vec<usize>,
+}
+
+impl<V: Copy> FlatVecs<V> {
+    pub fn new() -> Self {
+        FlatVecs {
+            data: Vec::new(),
+            offsets: vec![0],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&[V]> {
+        (idx < self.len()).then(|| &self.data[self.offsets[idx]..self.offsets[idx + 1]])
+    }
+
+    pub fn push(&mut self, v: &[V]) {
+        self.data.extend_from_slice(v);
+        self.offsets.push(self.data.len());
+    }
+
+    /// Same as `push`, with the vector read from an iterator (without collecting it first)
+    pub fn push_iter(&mut self, v: impl IntoIterator<Item = V>) {
+        self.data.extend(v);
+        self.offsets.push(self.data.len());
+    }
+
+    /// Remove and return the vector at `idx`, shifting the following ones.
+    /// Panics if `idx` is out of bounds.
+    pub fn remove(&mut self, idx: usize) -> Vec<V> {
+        let (start, end) = (self.offsets[idx], self.offsets[idx + 1]);
+        let v = self.data.drain(start..end).collect();
+        self.offsets.remove(idx + 1);
+        for o in &mut self.offsets[idx + 1..] {
+            *o -= end - start;
+        }
+        v
+    }
+
+    /// Keep the vectors with `keep[i]` set, compacting the buffer in place.
+    /// Releases the spare capacity if it is over half of the storage.
+    pub fn retain(&mut self, keep: &[bool]) {
+        assert_eq!(keep.len(), self.len());
+        let (mut n, mut len) = (0, 0);
+        for (i, &k) in keep.iter().enumerate() {
+            if k {
+                let (start, end) = (self.offsets[i], self.offsets[i + 1]);
+                self.data.copy_within(start..end, len);
+                len += end - start;
+                n += 1;
+                self.offsets[n] = len;
+            }
+        }
+        self.data.truncate(len);
+        self.offsets.truncate(n + 1);
+        if self.data.capacity() > 2 * self.data.len() {
+            self.data.shrink_to_fit();
+        }
+        if self.offsets.capacity() > 2 * self.offsets.len() {
+            self.offsets.shrink_to_fit();
+        }
+    }
+
+    /// Release the unused capacity, e.g. after bulk insertion
+    pub fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+        self.offsets.shrink_to_fit();
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Heap memory of the buffers, including the unused capacity
+    pub fn heap_bytes(&self) -> usize {
+        self.data.capacity() * std::mem::size_of::<V>()
+            + self.offsets.capacity() * std::mem::size_of::<usize>()
+    }
+}
+
+impl<V: Copy> Default for FlatVecs<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,262 +1,906 @@
+use crate::errors::{
+    extract_pairs, extract_usizes, index_error, io_error, range_error, store_error, store_error_in,
+    value_error,
+};
 use crate::packing_bestfit::fits_into_bestfit;
-use crate::packing_branching::fits_into_branching;
-use crate::packing_common::{counts_to_sizes, sizes_to_counts};
-use crate::{CVec, C};
+use crate::packing_common::{
+    check_counts, counts_to_sizes, try_counts, try_sizes_to_counts, Count, RangeError,
+};
+use crate::packing_sparse::{fits_into_bestfit_sparse, try_pairs_to_sparse};
+use crate::packing_witness::Packing;
+use crate::storage::{FitOptions, ItemSetStore, SetBuf, SetRef, DENSE_MAX_SIZE};
+use pyo3::buffer::{Element, PyBuffer};
 use pyo3::prelude::*;
-use rayon::iter::IntoParallelRefIterator;
-use rayon::iter::ParallelIterator;
-
-/// Each element is a set of items (Matej calls them "History"),
-/// encoded as counts of each size: the number of items of
-/// size `size` is `item_set.0[size]`
-#[pyclass]
-#[derive(Debug, Clone, Default)]
-#[pyo3(text_signature = "(all_counts=None, /, all_sizes=None)")]
-pub struct ItemSets(Vec<CVec>);
-
-#[pymethods]
-impl ItemSets {
-    /// Creates a new instance for the given item set
-    /// (given as an iterable of count vectors)
-    #[new]
-    #[args(all_counts = "None", all_sizes = "None")]
-    pub fn new(
-        all_counts: Option<&pyo3::types::PySequence>,
-        all_sizes: Option<&pyo3::types::PySequence>,
-    ) -> PyResult<Self> {
-        let mut s: Self = Default::default();
-        if let Some(cs) = all_counts {
-            for counts in cs.iter()? {
-                s.push_counts(counts?)?;
-            }
+use pyo3::types::{PyByteArray, PyBytes};
+use std::path::PathBuf;
+
+/// Generates an item set collection class with counts of the given type
+macro_rules! item_sets_class {
+    ($(#[$attr:meta])* $name:ident, $c:ty) => {
+        $(#[$attr])*
+        #[pyclass(module = "binpack_pyo3")]
+        #[derive(Debug, Clone)]
+        #[pyo3(text_signature = "(all_counts=None, /, all_sizes=None, max_size=None, sparse=False)")]
+        pub struct $name {
+            store: ItemSetStore<$c>,
         }
-        if let Some(ss) = all_sizes {
-            for sizes in ss.iter()? {
-                s.push_sizes(sizes?)?;
+
+        #[pymethods]
+        impl $name {
+            /// Creates a new instance for the given item set
+            /// (given as an iterable of count vectors, or a 2-D buffer such as a NumPy array of the count type)
+            ///
+            /// `max_size` limits the item sizes independently of the count type,
+            /// by default it is the maximum of the count type, capped at 2**20
+            /// for dense item sets as their count vectors are as long as the largest size.
+            ///
+            /// With `sparse=True`, the item sets are stored as `(size, count)` pairs of the
+            /// present sizes and the queries use the sparse variants of the algorithms,
+            /// so memory and time scale with the number of distinct sizes instead of the maximum size.
+            /// The given item sets of the queries can then be `(size, count)` pairs too, as a list
+            /// of tuples or a dict. The exact search (and the queries that always convert to count
+            /// vectors: `max_packable_volume_into_given`, `packing_into_given` and `save`) raise
+            /// `BinpackOverflowError` for sizes over 2**20 instead of allocating their count vectors.
+            #[new]
+            #[args(all_counts = "None", all_sizes = "None", max_size = "None", sparse = false)]
+            pub fn new(
+                all_counts: Option<&PyAny>,
+                all_sizes: Option<&pyo3::types::PySequence>,
+                max_size: Option<usize>,
+                sparse: bool,
+            ) -> PyResult<Self> {
+                let max_size = match max_size {
+                    Some(max_size) => max_size,
+                    None if sparse => <$c>::MAX_USIZE,
+                    None => <$c>::MAX_USIZE.min(DENSE_MAX_SIZE),
+                };
+                if sparse && max_size > u32::MAX as usize {
+                    return Err(value_error(format!(
+                        "Sparse item sets support sizes up to {}, got max_size={}",
+                        u32::MAX,
+                        max_size
+                    )));
+                }
+                let mut s = Self {
+                    store: ItemSetStore::new(max_size, sparse),
+                };
+                if let Some(cs) = all_counts {
+                    s.store = s.extract_all_counts(cs)?;
+                }
+                if let Some(ss) = all_sizes {
+                    for sizes in ss.iter()? {
+                        s.push_sizes(sizes?)?;
+                    }
+                    s.store.sets.shrink_to_fit();
+                }
+                Ok(s)
             }
-        }
-        Ok(s)
-    }
 
-    /// Convert count vector into sizes vector
-    #[staticmethod]
-    #[pyo3(text_signature = "(counts)")]
-    pub fn c2s(py: Python, counts: &PyAny) -> PyResult<PyObject> {
-        let cs: Vec<C> = counts.extract()?;
-        Ok(counts_to_sizes(&cs).into_py(py))
-    }
+            /// Convert count vector into sizes vector
+            #[staticmethod]
+            #[pyo3(text_signature = "(counts)")]
+            pub fn c2s(py: Python, counts: &PyAny) -> PyResult<PyObject> {
+                let cs = extract_usizes(counts)?;
+                let cs = try_counts::<$c>(&cs, usize::MAX).map_err(range_error)?;
+                Ok(counts_to_sizes(&cs).into_py(py))
+            }
 
-    /// Convert sizes vector into counts vector
-    #[staticmethod]
-    #[pyo3(text_signature = "(sizes)")]
-    pub fn s2c(py: Python, sizes: &PyAny) -> PyResult<PyObject> {
-        let ss: Vec<C> = sizes.extract()?;
-        Ok(sizes_to_counts(&ss).into_py(py))
-    }
+            /// Convert sizes vector into counts vector, with sizes up to `max_size`
+            /// (by default, the default `max_size` of dense item sets)
+            #[staticmethod]
+            #[args(max_size = "None")]
+            #[pyo3(text_signature = "(sizes, /, max_size=None)")]
+            pub fn s2c(py: Python, sizes: &PyAny, max_size: Option<usize>) -> PyResult<PyObject> {
+                let ss = extract_usizes(sizes)?;
+                let max_size = max_size.unwrap_or(<$c>::MAX_USIZE.min(DENSE_MAX_SIZE));
+                Ok(try_sizes_to_counts::<$c>(&ss, max_size)
+                    .map_err(range_error)?
+                    .into_py(py))
+            }
 
-    /// Insert new item given by counts
-    pub fn push_counts(&mut self, counts: &PyAny) -> PyResult<()> {
-        let cs: Vec<C> = counts.extract()?;
-        assert!(cs.len() < C::MAX as usize);
-        assert!(cs.len() == 0 || cs[0] == 0);
-        self.0.push(cs.into());
-        Ok(())
-    }
+            /// Insert new item given by counts
+            pub fn push_counts(&mut self, counts: &PyAny) -> PyResult<()> {
+                let cs = self.extract_counts(counts)?;
+                self.store.push_counts(cs.as_ref()).map_err(store_error)
+            }
 
-    /// Insert new item given by counts
-    pub fn push_sizes(&mut self, sizes: &PyAny) -> PyResult<()> {
-        let ss: Vec<C> = sizes.extract()?;
-        assert!(ss.len() < C::MAX as usize);
-        let cs = sizes_to_counts(&ss);
-        assert_eq!(cs[0], 0, "Items of size 0 not allowed.");
-        self.0.push(cs.into());
-        Ok(())
-    }
+            /// Insert new item given by counts
+            pub fn push_sizes(&mut self, sizes: &PyAny) -> PyResult<()> {
+                let ss = extract_usizes(sizes)?;
+                self.store.push_sizes(&ss).map_err(store_error)
+            }
 
-    pub fn all_counts(&self) -> Vec<Vec<C>> {
-        self.0.iter().map(|c| c.clone().into()).collect()
-    }
+            /// The maximum allowed item size
+            #[getter]
+            pub fn max_size(&self) -> usize {
+                self.store.max_size()
+            }
 
-    pub fn all_sizes(&self) -> Vec<Vec<C>> {
-        self.0.iter().map(|c| counts_to_sizes(c)).collect()
-    }
+            /// Whether the item sets are stored sparse
+            #[getter]
+            pub fn sparse(&self) -> bool {
+                self.store.is_sparse()
+            }
 
-    pub fn __len__(&self) -> usize {
-        self.0.len()
-    }
+            /// Whether the item sets are memory-mapped from a file (read-only), see `open`
+            #[getter]
+            pub fn mapped(&self) -> bool {
+                self.store.is_mapped()
+            }
 
-    pub fn __getitem__(&self, idx: usize) -> PyResult<Vec<C>> {
-        self.0
-            .get(idx)
-            .cloned()
-            .map(|c| c.into())
-            .ok_or_else(|| pyo3::exceptions::PyIndexError::new_err("Out of bounds"))
-    }
+            pub fn all_counts(&self) -> Vec<Vec<$c>> {
+                self.store.iter().map(|s| s.to_dense()).collect()
+            }
 
-    pub fn __repr__(&self) -> String {
-        format!("ItemsSet(all_counts={:?})", self.0)
-    }
+            pub fn all_sizes(&self) -> Vec<Vec<usize>> {
+                self.store.iter().map(|s| s.to_sizes()).collect()
+            }
 
-    /// Return an estimate of the memory used by the ItemsSet.
-    /// Also includes the vector headers and any unused vector capacity, does not include padding.
-    pub fn memory_used(&self) -> usize {
-        // Size of a Vec header, same for Vec<Vec<C>>
-        let vs = std::mem::size_of::<Vec<C>>();
-        return vs
-            + self.0.capacity() * vs
-            + self
-                .0
-                .iter()
-                .map(|v| v.capacity() * std::mem::size_of::<C>())
-                .sum::<usize>();
-    }
+            /// Return all the item sets as a 2-D NumPy array of counts (of the count type),
+            /// padded with zeros to the longest count vector
+            pub fn all_counts_array(&self, py: Python) -> PyResult<PyObject> {
+                let width = self.store.iter().map(|s| s.dense_len()).max().unwrap_or(0);
+                let bytes_per_row = width * std::mem::size_of::<$c>();
+                // The rows are written straight into the bytearray backing the array
+                let mut row = vec![0 as $c; width];
+                let bytes = PyByteArray::new_with(py, self.store.len() * bytes_per_row, |bytes| {
+                    for (out, s) in bytes.chunks_mut(bytes_per_row.max(1)).zip(self.store.iter()) {
+                        row.fill(0);
+                        s.write_dense(&mut row);
+                        for (o, c) in out.chunks_mut(std::mem::size_of::<$c>()).zip(&row) {
+                            o.copy_from_slice(&c.to_ne_bytes());
+                        }
+                    }
+                    Ok(())
+                })?;
+                let dtype = format!("u{}", std::mem::size_of::<$c>());
+                Ok(py
+                    .import("numpy")?
+                    .call_method1("frombuffer", (bytes, dtype))?
+                    .call_method1("reshape", ((self.store.len(), width),))?
+                    .into_py(py))
+            }
 
-    /// Check if any of the stored item sets fit into the item set given by `counts`.
-    ///
-    /// `par` invokes parallelism, `branching=0` means best-fit, higher values
-    /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
-    #[args(par = false, branching = 0)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, branching=0)")]
-    pub fn any_fit_into_given(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        branching: usize,
-    ) -> PyResult<bool> {
-        self.any_f_helper(counts, par, |sc, gc| fits_into_branching(sc, gc, branching))
-    }
+            pub fn __len__(&self) -> usize {
+                self.store.len()
+            }
 
-    /// Check if the item set given by `counts` fits into any of the stored item sets.
-    ///
-    /// `par` invokes parallelism, `branching=0` means best-fit, higher values
-    /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
-    #[args(par = false, branching = 0)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, branching=0)")]
-    pub fn given_fits_into_any(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        branching: usize,
-    ) -> PyResult<bool> {
-        self.any_f_helper(counts, par, |sc, gc| fits_into_branching(gc, sc, branching))
-    }
+            /// The item set at index `idx` as counts (negative indices count from the end)
+            pub fn __getitem__(&self, idx: isize) -> PyResult<Vec<$c>> {
+                Ok(self.store.get(self.index(idx)?).unwrap().to_dense())
+            }
 
-    /// Check if all of the stored item sets fit into the item set given by `counts`.
-    ///
-    /// `par` invokes parallelism, `branching=0` means best-fit, higher values
-    /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
-    #[args(par = false, branching = 0)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, branching=0)")]
-    pub fn all_fit_into_given(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        branching: usize,
-    ) -> PyResult<bool> {
-        self.all_f_helper(counts, par, |sc, gc| fits_into_branching(sc, gc, branching))
-    }
+            /// Remove the item set at index `idx` (negative indices count from the end)
+            #[pyo3(text_signature = "($self, idx)")]
+            pub fn remove(&mut self, idx: isize) -> PyResult<()> {
+                let idx = self.index(idx)?;
+                self.store.remove(idx).map_err(store_error)?;
+                Ok(())
+            }
 
-    /// Check if the item set given by `counts` fits into all of the stored item sets.
-    ///
-    /// `par` invokes parallelism, `branching=0` means best-fit, higher values
-    /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
-    #[args(par = false, branching = 0)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, branching=0)")]
-    pub fn given_fits_into_all(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        branching: usize,
-    ) -> PyResult<bool> {
-        self.all_f_helper(counts, par, |sc, gc| fits_into_branching(gc, sc, branching))
-    }
+            /// Remove the item set at index `idx` (the last one by default) and return it as counts
+            #[args(idx = "-1")]
+            #[pyo3(text_signature = "($self, idx=-1)")]
+            pub fn pop(&mut self, idx: isize) -> PyResult<Vec<$c>> {
+                let idx = self.index(idx)?;
+                self.store.remove(idx).map_err(store_error)
+            }
 
-    /// Count how many of the stored item sets fit into the item set given by `counts`.
-    ///
-    /// `par` invokes parallelism, `branching=0` means best-fit, higher values
-    /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
-    #[args(par = false, branching = 0)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, branching=0)")]
-    pub fn how_many_fit_into_given(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        branching: usize,
-    ) -> PyResult<usize> {
-        self.count_f_helper(counts, par, |sc, gc| fits_into_branching(sc, gc, branching))
-    }
+            pub fn __delitem__(&mut self, idx: &PyAny) -> PyResult<()> {
+                if let Ok(slice) = idx.downcast::<pyo3::types::PySlice>() {
+                    let ind = slice.indices(self.store.len() as std::os::raw::c_long)?;
+                    let mut keep = vec![true; self.store.len()];
+                    for k in 0..ind.slicelength {
+                        keep[(ind.start + k * ind.step) as usize] = false;
+                    }
+                    self.store.retain(&keep).map_err(store_error)
+                } else {
+                    self.remove(idx.extract()?)
+                }
+            }
 
-    /// Count into how many of the stored item sets does the item set given by `counts` fit.
-    ///
-    /// `par` invokes parallelism, `branching=0` means best-fit, higher values
-    /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
-    #[args(par = false, branching = 0)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, branching=0)")]
-    pub fn given_fits_into_how_many(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        branching: usize,
-    ) -> PyResult<usize> {
-        self.count_f_helper(counts, par, |sc, gc| fits_into_branching(gc, sc, branching))
-    }
+            /// Remove all the item sets
+            pub fn clear(&mut self) -> PyResult<()> {
+                self.store.clear().map_err(store_error)
+            }
 
-    /// Check if any of the stored item sets fit into the item set given by `counts` (for benchmark only).
-    ///
-    /// This `par` invokes parallelism (set ), with `trim_upper=True` first looks for any necessary packing of
-    /// largest items into the only one larger bins, repeatedly.
-    #[args(par = false, trim_upper = false)]
-    #[pyo3(text_signature = "($self, counts, /, par=False, trim_upper=False)")]
-    pub fn bestfit_any_fit_into_given(
-        &self,
-        counts: &PyAny,
-        par: bool,
-        trim_upper: bool,
-    ) -> PyResult<bool> {
-        self.any_f_helper(counts, par, |sc, gc| fits_into_bestfit(sc, gc, trim_upper))
-    }
-}
+            /// Keep only the item sets where `mask` (an iterable of truth values, one per item set,
+            /// e.g. a list of bools or 0/1, or a NumPy bool array) is true
+            #[pyo3(text_signature = "($self, mask)")]
+            pub fn retain(&mut self, mask: &PyAny) -> PyResult<()> {
+                let mask = mask
+                    .iter()?
+                    .map(|x| x?.is_true())
+                    .collect::<PyResult<Vec<bool>>>()?;
+                if mask.len() != self.store.len() {
+                    return Err(value_error(format!(
+                        "Mask of length {} given for {} item sets",
+                        mask.len(),
+                        self.store.len()
+                    )));
+                }
+                self.store.retain(&mask).map_err(store_error)
+            }
+
+            pub fn __repr__(&self) -> String {
+                if self.store.is_sparse() {
+                    format!(
+                        concat!(stringify!($name), "(all_sizes={:?}, sparse=True)"),
+                        self.all_sizes()
+                    )
+                } else {
+                    format!(
+                        concat!(stringify!($name), "(all_counts={:?})"),
+                        self.all_counts()
+                    )
+                }
+            }
+
+            /// Return the state for pickling: `(format version, max_size, sparse, encoded item sets)`
+            pub fn __getstate__(&self, py: Python) -> PyObject {
+                (
+                    PICKLE_VERSION,
+                    self.store.max_size,
+                    self.sparse(),
+                    PyBytes::new(py, &self.store.encode()),
+                )
+                    .into_py(py)
+            }
+
+            /// Restore the state from `__getstate__`, replacing all the item sets
+            pub fn __setstate__(&mut self, state: &PyAny) -> PyResult<()> {
+                let (version, max_size, sparse, data): (u32, usize, bool, &[u8]) = state.extract()?;
+                if version != PICKLE_VERSION {
+                    return Err(value_error(format!("Unsupported pickle version {}", version)));
+                }
+                self.store = ItemSetStore::decode(data, sparse, max_size).map_err(value_error)?;
+                Ok(())
+            }
+
+            pub fn __reduce__(slf: &PyCell<Self>) -> (PyObject, PyObject, PyObject) {
+                let py = slf.py();
+                (
+                    slf.get_type().into_py(py),
+                    pyo3::types::PyTuple::empty(py).into_py(py),
+                    slf.borrow().__getstate__(py),
+                )
+            }
+
+            /// Save the item sets (as count vectors) to the file `path` in a versioned binary format
+            /// with checksums, see `file_format.rs` for the specification
+            #[pyo3(text_signature = "($self, path)")]
+            pub fn save(&self, py: Python, path: PathBuf) -> PyResult<()> {
+                py.allow_threads(|| self.store.save(&path))
+                    .map_err(|e| io_error(&path, e))
+            }
+
+            /// Load item sets saved with `save`, validating the whole file.
+            /// The count type of the file must match the class, `max_size` is restored from the file.
+            #[staticmethod]
+            #[args(sparse = false)]
+            #[pyo3(text_signature = "(path, /, sparse=False)")]
+            pub fn load(py: Python, path: PathBuf, sparse: bool) -> PyResult<Self> {
+                let store = py
+                    .allow_threads(|| ItemSetStore::load(&path, sparse))
+                    .map_err(|e| io_error(&path, e))?;
+                Ok(Self { store })
+            }
+
+            /// Open item sets saved with `save` memory-mapped and read-only: the count vectors are
+            /// paged in by the OS on access, so the file may be larger than the memory. All the queries
+            /// are supported (with `par=True` over the mapped item sets), modifying methods raise `TypeError`.
+            ///
+            /// With `verify=True`, the checksums and all the count vectors are checked first
+            /// (reading the whole file once), otherwise only the header and the offsets.
+            /// The file must not be modified while open.
+            #[staticmethod]
+            #[args(verify = true)]
+            #[pyo3(text_signature = "(path, /, verify=True)")]
+            pub fn open(py: Python, path: PathBuf, verify: bool) -> PyResult<Self> {
+                let store = py
+                    .allow_threads(|| ItemSetStore::open(&path, verify))
+                    .map_err(|e| io_error(&path, e))?;
+                Ok(Self { store })
+            }
+
+            pub fn __copy__(&self) -> Self {
+                self.clone()
+            }
+
+            pub fn __deepcopy__(&self, _memo: &PyAny) -> Self {
+                self.clone()
+            }
 
-impl ItemSets {
-    fn count_f_helper<F>(&self, counts: &PyAny, par: bool, f: F) -> PyResult<usize>
-    where
-        F: Fn(&[C], &[C]) -> bool + Sync,
-    {
-        let gc: Vec<C> = counts.extract()?;
-        assert!(gc.len() < C::MAX as usize);
-        assert!(gc.len() == 0 || gc[0] == 0);
-        if par {
-            Ok(self.0.par_iter().filter(|sc| f(sc, &gc)).count())
-        } else {
-            Ok(self.0.iter().filter(|sc| f(sc, &gc)).count())
+            /// Return an estimate of the memory used by the ItemSets.
+            /// Includes the offsets table and any unused buffer capacity.
+            /// Does not include memory-mapped files (cached by the OS).
+            pub fn memory_used(&self) -> usize {
+                self.store.memory_used()
+            }
+
+            /// Check if any of the stored item sets fit into the item set given by `counts`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn any_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<bool> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.any_fit_into_given(gc, opts)
+                })
+            }
+
+            /// Check if the item set given by `counts` fits into any of the stored item sets.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn given_fits_into_any(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<bool> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.given_fits_into_any(gc, opts)
+                })
+            }
+
+            /// Check if all of the stored item sets fit into the item set given by `counts`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn all_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<bool> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.all_fit_into_given(gc, opts)
+                })
+            }
+
+            /// Check if the item set given by `counts` fits into all of the stored item sets.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn given_fits_into_all(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<bool> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.given_fits_into_all(gc, opts)
+                })
+            }
+
+            /// Count how many of the stored item sets fit into the item set given by `counts`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn how_many_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<usize> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.how_many_fit_into_given(gc, opts)
+                })
+            }
+
+            /// Count into how many of the stored item sets does the item set given by `counts` fit.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn given_fits_into_how_many(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<usize> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.given_fits_into_how_many(gc, opts)
+                })
+            }
+
+            /// Return the sorted indices of the stored item sets that fit into the item set given by `counts`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn which_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<usize>> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.which_fit_into_given(gc, opts)
+                })
+            }
+
+            /// Return the sorted indices of the stored item sets into which the item set given by `counts` fits.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn given_fits_into_which(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<usize>> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.given_fits_into_which(gc, opts)
+                })
+            }
+
+            /// Return the lowest index of a stored item set that fits into the item set given by `counts`,
+            /// or `None`. The result is the same with `par=True`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn first_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Option<usize>> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.first_fit_into_given(gc, opts)
+                })
+            }
+
+            /// For each of the item sets given by `all_counts`, check if any of the stored item sets fit into it.
+            ///
+            /// `par` parallelizes over all the (given, stored) pairs, `branching` and `exact` are as in the other queries.
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, all_counts, /, par=False, branching=0, exact=False)")]
+            pub fn any_fit_into_each(
+                &self,
+                all_counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<bool>> {
+                let given = self.extract_all_counts(all_counts)?;
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                all_counts
+                    .py()
+                    .allow_threads(|| self.store.any_fit_into_each(&given, opts))
+                    .map_err(range_error)
+            }
+
+            /// For each of the item sets given by `all_counts`, check if it fits into any of the stored item sets.
+            ///
+            /// `par` parallelizes over all the (given, stored) pairs, `branching` and `exact` are as in the other queries.
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, all_counts, /, par=False, branching=0, exact=False)")]
+            pub fn each_fits_into_any(
+                &self,
+                all_counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<bool>> {
+                let given = self.extract_all_counts(all_counts)?;
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                all_counts
+                    .py()
+                    .allow_threads(|| self.store.each_fits_into_any(&given, opts))
+                    .map_err(range_error)
+            }
+
+            /// For each of the item sets given by `all_counts`, count how many of the stored item sets fit into it.
+            ///
+            /// `par` parallelizes over all the (given, stored) pairs, `branching` and `exact` are as in the other queries.
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, all_counts, /, par=False, branching=0, exact=False)")]
+            pub fn how_many_fit_into_each(
+                &self,
+                all_counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<usize>> {
+                let given = self.extract_all_counts(all_counts)?;
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                all_counts
+                    .py()
+                    .allow_threads(|| self.store.how_many_fit_into_each(&given, opts))
+                    .map_err(range_error)
+            }
+
+            /// For each of the item sets given by `all_counts`, count into how many of the stored item sets it fits.
+            ///
+            /// `par` parallelizes over all the (given, stored) pairs, `branching` and `exact` are as in the other queries.
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, all_counts, /, par=False, branching=0, exact=False)")]
+            pub fn each_fits_into_how_many(
+                &self,
+                all_counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<usize>> {
+                let given = self.extract_all_counts(all_counts)?;
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                all_counts
+                    .py()
+                    .allow_threads(|| self.store.each_fits_into_how_many(&given, opts))
+                    .map_err(range_error)
+            }
+
+            /// Compute the relation "stored item set `i` fits into item set `j` of `other`" for all the pairs.
+            ///
+            /// Returns `bytes` with the rows of the matrix bit-packed, each row taking `ceil(len(other)/8)` bytes,
+            /// most significant bit first (unpack e.g. with `numpy.unpackbits`), or with `sparse=True`
+            /// the sorted list of the `(i, j)` pairs. `par` parallelizes over tiles of the matrix,
+            /// `branching` and `exact` are as in the other queries.
+            #[args(branching = 0, exact = false, par = false, sparse = false)]
+            #[pyo3(text_signature = "($self, other, /, branching=0, exact=False, par=False, sparse=False)")]
+            pub fn fit_matrix(
+                &self,
+                py: Python,
+                other: PyRef<$name>,
+                branching: usize,
+                exact: bool,
+                par: bool,
+                sparse: bool,
+            ) -> PyResult<PyObject> {
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                let (store, other) = (&self.store, &other.store);
+                Ok(if sparse {
+                    py.allow_threads(|| store.fit_matrix(other, opts))
+                        .map_err(range_error)?
+                        .into_py(py)
+                } else {
+                    let bits = py
+                        .allow_threads(|| store.fit_matrix_packed(other, opts))
+                        .map_err(range_error)?;
+                    PyBytes::new(py, &bits).into_py(py)
+                })
+            }
+
+            /// Count how many of the stored item sets provably fit, provably do not fit, and are undecided
+            /// when packed into the item set given by `counts`. Returns `(fits, does_not_fit, unknown)`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`), never undecided.
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn classify_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<(usize, usize, usize)> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.classify_fit_into_given(gc, opts)
+                })
+            }
+
+            /// Count into how many of the stored item sets the item set given by `counts` provably fits,
+            /// provably does not fit, and is undecided. Returns `(fits, does_not_fit, unknown)`.
+            ///
+            /// `par` invokes parallelism, `branching=0` means best-fit, higher values
+            /// do a partial exhaustive search limiting the branch count (switching to best-fit afterwards).
+            /// `exact=True` runs a complete exhaustive search instead (ignoring `branching`), never undecided.
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn classify_given_fits_into(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<(usize, usize, usize)> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.classify_given_fits_into(gc, opts)
+                })
+            }
+
+            /// For every stored item set, the maximum total size of its items that can be packed
+            /// into the item set given by `counts` (the total size of all of them if they fit).
+            ///
+            /// `par` invokes parallelism. The heuristic packs the items best-fit, largest first, skipping
+            /// those that do not fit, and adds back skipped items while the search with `branching` finds a packing.
+            /// `exact=True` finds the maximum by a branch-and-bound search (exponential in the worst case).
+            #[args(par = false, branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, branching=0, exact=False)")]
+            pub fn max_packable_volume_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Vec<u64>> {
+                self.query(counts, par, branching, exact, |gc, opts| {
+                    self.store.max_packable_volume_into_given(gc, opts)
+                })
+            }
+
+            /// Insert the item set given by `counts` unless it is dominated by a stored item set,
+            /// evicting the stored item sets it dominates. Returns whether it was inserted.
+            ///
+            /// With `direction="max"`, a set is dominated when it fits into another one (keeping the maximal sets),
+            /// with `direction="min"` when another one fits into it (keeping the minimal sets).
+            /// Only found packings count, so with heuristics some dominated sets may be kept.
+            /// `par`, `branching` and `exact` are as in the other queries.
+            #[args(direction = "\"max\"", par = false, branching = 0, exact = false)]
+            #[pyo3(
+                text_signature = "($self, counts, /, direction=\"max\", par=False, branching=0, exact=False)"
+            )]
+            pub fn push_if_not_dominated(
+                &mut self,
+                counts: &PyAny,
+                direction: &str,
+                par: bool,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<bool> {
+                let keep_max = match direction {
+                    "max" => true,
+                    "min" => false,
+                    _ => {
+                        return Err(value_error(format!(
+                            "Invalid direction {:?}, use \"max\" or \"min\"",
+                            direction
+                        )))
+                    }
+                };
+                let gc = self.extract_counts(counts)?;
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                let store = &mut self.store;
+                counts
+                    .py()
+                    .allow_threads(|| store.push_if_not_dominated(gc.as_ref(), keep_max, opts))
+                    .map_err(store_error)
+            }
+
+            /// Find a packing of the `idx`-th stored item set (negative indices count from the end)
+            /// into the item set given by `counts`.
+            ///
+            /// Returns every bin of `counts` as `(bin size, [item sizes])`, or `None` if no packing was found.
+            /// `branching` and `exact` select the algorithm as in the other queries.
+            #[args(branching = 0, exact = false)]
+            #[pyo3(text_signature = "($self, idx, counts, /, branching=0, exact=False)")]
+            pub fn packing_into_given(
+                &self,
+                idx: isize,
+                counts: &PyAny,
+                branching: usize,
+                exact: bool,
+            ) -> PyResult<Option<Packing>> {
+                let idx = self.index(idx)?;
+                self.query(counts, false, branching, exact, |gc, opts| {
+                    self.store.packing_into_given(idx, gc, opts)
+                })
+            }
+
+            /// Check if any of the stored item sets fit into the item set given by `counts` (for benchmark only).
+            ///
+            /// This `par` invokes parallelism (set ), with `trim_upper=True` first looks for any necessary packing of
+            /// largest items into the only one larger bins, repeatedly.
+            #[args(par = false, trim_upper = false)]
+            #[pyo3(text_signature = "($self, counts, /, par=False, trim_upper=False)")]
+            pub fn bestfit_any_fit_into_given(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                trim_upper: bool,
+            ) -> PyResult<bool> {
+                let gc = self.extract_counts(counts)?;
+                let gc = self.store.given(gc.as_ref()).map_err(range_error)?;
+                let gc = gc.as_ref();
+                Ok(counts.py().allow_threads(|| {
+                    self.store.sets.any(par, |sc| match (sc, gc) {
+                        (SetRef::Sparse(sc), SetRef::Sparse(gc)) => {
+                            fits_into_bestfit_sparse(sc, gc, trim_upper).fits()
+                        }
+                        _ => fits_into_bestfit(&sc.to_dense(), &gc.to_dense(), trim_upper).fits(),
+                    })
+                }))
+            }
         }
-    }
 
-    fn any_f_helper<F>(&self, counts: &PyAny, par: bool, f: F) -> PyResult<bool>
-    where
-        F: Fn(&[C], &[C]) -> bool + Sync,
-    {
-        let gc: Vec<C> = counts.extract()?;
-        assert!(gc.len() < C::MAX as usize);
-        assert!(gc.len() == 0 || gc[0] == 0);
-        if par {
-            Ok(self.0.par_iter().any(|sc| f(sc, &gc)))
-        } else {
-            Ok(self.0.iter().any(|sc| f(sc, &gc)))
+        impl $name {
+            /// Resolve a Python index (negative from the end), checking the bounds
+            fn index(&self, idx: isize) -> PyResult<usize> {
+                let len = self.store.len();
+                let i = if idx < 0 { idx + len as isize } else { idx };
+                if i < 0 || i as usize >= len {
+                    return Err(index_error(idx, len));
+                }
+                Ok(i as usize)
+            }
+
+            /// Extract a count vector (copied from a 1-D buffer of the count type without any
+            /// per-element Python conversions) or `(size, count)` pairs, checking the item size range
+            fn extract_counts(&self, counts: &PyAny) -> PyResult<SetBuf<$c>> {
+                let max_size = self.store.max_size;
+                if let Some(cs) = read_buffer::<$c>(counts)? {
+                    check_counts(&cs, max_size).map_err(range_error)?;
+                    return Ok(SetBuf::Dense(cs));
+                }
+                if let Some(pairs) = extract_pairs(counts)? {
+                    let sc = try_pairs_to_sparse(&pairs, max_size).map_err(range_error)?;
+                    return Ok(SetBuf::Sparse(sc));
+                }
+                let cs = extract_usizes(counts)?;
+                Ok(SetBuf::Dense(
+                    try_counts::<$c>(&cs, max_size).map_err(range_error)?,
+                ))
+            }
+
+            /// Run the store query `f` on the item set given by `counts` with the GIL released,
+            /// so other Python threads can run (and query the same item sets) meanwhile
+            fn query<R, F>(
+                &self,
+                counts: &PyAny,
+                par: bool,
+                branching: usize,
+                exact: bool,
+                f: F,
+            ) -> PyResult<R>
+            where
+                F: FnOnce(SetRef<$c>, FitOptions) -> Result<R, RangeError> + Send,
+                R: Send,
+            {
+                let gc = self.extract_counts(counts)?;
+                let opts = FitOptions {
+                    branching,
+                    exact,
+                    par,
+                };
+                counts
+                    .py()
+                    .allow_threads(|| f(gc.as_ref(), opts))
+                    .map_err(range_error)
+            }
+
+            /// Extract a sequence of count vectors, as in `extract_counts`
+            /// (from a 2-D buffer of the count type with one count vector per row),
+            /// into item sets with the `max_size` and representation of these
+            fn extract_all_counts(&self, all_counts: &PyAny) -> PyResult<ItemSetStore<$c>> {
+                let mut given = ItemSetStore::new(self.store.max_size, self.store.is_sparse());
+                if !push_buffer_rows(&mut given, all_counts)? {
+                    for counts in all_counts.iter()? {
+                        let cs = self.extract_counts(counts?)?;
+                        given.push_counts(cs.as_ref()).map_err(store_error)?;
+                    }
+                }
+                given.sets.shrink_to_fit();
+                Ok(given)
+            }
         }
+    };
+}
+
+item_sets_class!(
+    /// Each element is a set of items (Matej calls them "History"),
+    /// encoded as counts of each size: the number of items of
+    /// size `size` is `item_set.0[size]`. Counts are stored as `u8`.
+    ItemSets,
+    u8
+);
+
+item_sets_class!(
+    /// Same as `ItemSets`, with counts (and sizes) stored as `u16`.
+    ItemSets16,
+    u16
+);
+
+item_sets_class!(
+    /// Same as `ItemSets`, with counts (and sizes) stored as `u32`.
+    ItemSets32,
+    u32
+);
+
+/// Read `obj` if it is a 1-D buffer of `T` (copied once, without per-element Python conversions).
+/// Returns `None` for other objects (to be read as sequences).
+fn read_buffer<T: Element + Copy>(obj: &PyAny) -> PyResult<Option<Vec<T>>> {
+    match PyBuffer::<T>::get(obj) {
+        Ok(buf) if buf.dimensions() == 1 => Ok(Some(buf.to_vec(obj.py())?)),
+        _ => Ok(None),
     }
+}
 
-    fn all_f_helper<F>(&self, counts: &PyAny, par: bool, f: F) -> PyResult<bool>
-    where
-        F: Fn(&[C], &[C]) -> bool + Sync,
-    {
-        let gc: Vec<C> = counts.extract()?;
-        assert!(gc.len() < C::MAX as usize);
-        assert!(gc.len() == 0 || gc[0] == 0);
-        if par {
-            Ok(self.0.par_iter().all(|sc| f(sc, &gc)))
-        } else {
-            Ok(self.0.iter().all(|sc| f(sc, &gc)))
+/// Push the rows of `obj` into `store` if it is a 2-D buffer of `T`, one count vector per row.
+/// Returns `false` for other objects (to be read as sequences).
+///
+/// The rows of C-contiguous buffers are copied straight from the buffer memory into the storage,
+/// other buffers are first copied into one contiguous vector.
+fn push_buffer_rows<T: Count + Element>(
+    store: &mut ItemSetStore<T>,
+    obj: &PyAny,
+) -> PyResult<bool> {
+    let buf = match PyBuffer::<T>::get(obj) {
+        Ok(buf) if buf.dimensions() == 2 => buf,
+        _ => return Ok(false),
+    };
+    let (rows, width) = (buf.shape()[0], buf.shape()[1]);
+    if let Some(cells) = buf.as_slice(obj.py()) {
+        for i in 0..rows {
+            let row = cells[i * width..(i + 1) * width].iter().map(|c| c.get());
+            store
+                .push_counts_iter(row)
+                .map_err(|e| store_error_in(i, e))?;
+        }
+    } else {
+        let all = buf.to_vec(obj.py())?;
+        for i in 0..rows {
+            let row = &all[i * width..(i + 1) * width];
+            store.push_counts(row).map_err(|e| store_error_in(i, e))?;
         }
     }
+    Ok(true)
 }
+
+/// Version of the pickled state, see `__getstate__`
+const PICKLE_VERSION: u32 = 1;
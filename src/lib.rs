@@ -1,23 +1,72 @@
-use pyo3::prelude::*;
+//! Bin packing checks between sets of items and storage of large item set collections.
+//!
+//! Item sets are given as counts of items of every size (`counts[size]`, with `counts[0] == 0`),
+//! with counts of any `Count` type (`u8`, `u16` or `u32`). The checks of whether the "items" `a`
+//! fit into the "bins" `b` are `fits_into_bestfit`, `fits_into_branching` and `fits_into_exact`
+//! (and the sparse variants), `ItemSetStore` stores a collection of item sets and runs the checks
+//! against all of them.
+//!
+//! The core is a pure Rust library, the Python bindings (the `binpack_pyo3` module with the
+//! `ItemSets` classes) are built with the `python` feature, enabled by default.
+//! Use `default-features = false` to depend on the Rust API only.
+//!
+//! ```
+//! use binpack_pyo3::{fits_into_branching, sizes_to_counts, FitOptions, ItemSetStore};
+//!
+//! let bins = sizes_to_counts::<u8>(&[4, 4]);
+//! assert!(!fits_into_branching(&sizes_to_counts(&[3, 3, 2]), &bins, 10).fits());
+//!
+//! let mut store = ItemSetStore::<u8>::new(255, false);
+//! store.push_sizes(&[3, 1, 2]).unwrap();
+//! store.push_sizes(&[5]).unwrap();
+//! let opts = FitOptions { branching: 10, ..Default::default() };
+//! assert_eq!(store.which_fit_into_given(&bins, opts).unwrap(), vec![0]);
+//! ```
 
-// Fixed types for elements
+// The pyo3 0.16 macros expand to impls and cfgs that newer rustc flags as non-local resp. unexpected
+#![cfg_attr(feature = "python", allow(non_local_definitions, unexpected_cfgs))]
+
+// Default type for elements (as in `ItemSets`), see `packing_common::Count` for all the supported types
 pub type C = u8;
-// Type of vectors of elements *for storage only* - other interfaces can still use Vec<C>
-pub type CVec = Vec<C>;
 
+mod encoding;
+#[cfg(feature = "python")]
+mod errors;
+mod file_format;
+mod flat_vecs;
+#[cfg(feature = "python")]
 mod item_sets;
 mod packing_bestfit;
 mod packing_branching;
 mod packing_common;
-pub use item_sets::ItemSets;
-
-// Init
+mod packing_exact;
+mod packing_min_bins;
+mod packing_sparse;
+mod packing_subset;
+mod packing_variable;
+mod packing_witness;
+#[cfg(feature = "python")]
+mod python;
+mod storage;
 
-#[pymodule]
-fn binpack_pyo3(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_class::<ItemSets>()?;
-    Ok(())
-}
+#[cfg(feature = "python")]
+pub use item_sets::{ItemSets, ItemSets16, ItemSets32};
+pub use packing_bestfit::fits_into_bestfit;
+pub use packing_branching::fits_into_branching;
+pub use packing_common::{
+    check_counts, counts_to_sizes, sizes_to_counts, try_counts, try_sizes_to_counts, Count,
+    FitResult, RangeError,
+};
+pub use packing_exact::fits_into_exact;
+pub use packing_min_bins::{min_bins, MinBins, MAX_EXACT_CAPACITY};
+pub use packing_sparse::{
+    dense_to_sparse, fits_into_bestfit_sparse, fits_into_branching_sparse, sparse_to_dense,
+    SparseCounts,
+};
+pub use packing_subset::{max_packable, MaxPackable};
+pub use packing_variable::{cheapest_bins, BinType, CheapestBins};
+pub use packing_witness::{packing_into_branching, packing_into_exact, verify_packing, Packing};
+pub use storage::{FitOptions, ItemSetStore, SetRef, StoreError};
 
 // Tests
 
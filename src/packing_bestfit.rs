@@ -1,5 +1,4 @@
-use crate::packing_common::{item_sum, prep_diff, trim_upper_bins};
-use crate::C;
+use crate::packing_common::{item_sum, prep_diff, trim_upper_bins_ni, Count, FitResult, MoveLog};
 use std::cmp::min;
 
 /// Check if "items" `a` fit into "bins" `b` via best-fit matching.
@@ -8,7 +7,7 @@ use std::cmp::min;
 ///
 /// This works by substracting `d=a-b` and then trying to eliminate all
 /// positive numbers in `d` from smallest indices up.
-/// ```
+/// ```text
 /// a-b = ....+.+...-...-..+..---.++.-....
 ///           ^     ^                    
 ///           pi    ni
@@ -18,53 +17,71 @@ use std::cmp::min;
 /// are irellevant and thus erased. `sd` track the sum of the item
 /// sizes and is updated by forgotten negative bins, so we can stop if
 /// it is ever positive.
+///
+/// Returns `DoesNotFit` only when failing before the first best-fit choice
+/// (e.g. when the volume is exceeded), `Unknown` on later failures.
+///
+/// # Panics
+///
+/// May panic if `a[0] != 0` (items of size 0 are not allowed), validate untrusted counts with `check_counts`.
 #[allow(non_snake_case)]
-pub fn fits_into_bestfit(a: &[C], b: &[C], trim_upper: bool) -> bool {
+pub fn fits_into_bestfit<T: Count>(a: &[T], b: &[T], trim_upper: bool) -> FitResult {
     let mut d = prep_diff(a, b);
-    let sd = item_sum(&d) as i32;
-    fits_into_bestfit_internal(&mut d, sd, trim_upper)
+    let sd = item_sum(&d);
+    fits_into_bestfit_internal(&mut d, sd, trim_upper, &mut ())
 }
 
-pub fn fits_into_bestfit_internal(d: &mut [i32], sd: i32, trim_upper: bool) -> bool {
+/// Best-fit on a prepared difference vector `d` with item sum `sd`, recording the moves into `log`.
+/// On failure, `log` may contain some moves of the failed attempt.
+pub fn fits_into_bestfit_internal<L: MoveLog>(
+    d: &mut [i64],
+    sd: i64,
+    trim_upper: bool,
+    log: &mut L,
+) -> FitResult {
     if trim_upper {
-        if !trim_upper_bins(d) {
-            return false;
+        if !trim_upper_bins_ni(d, &mut 0, log) {
+            return FitResult::DoesNotFit;
         }
-        debug_assert_eq!(item_sum(d) as i32, sd);
+        debug_assert_eq!(item_sum(d), sd);
     }
     let mut ni = 0;
     let mut sd = sd;
+    // Set after the first (non-forced) best-fit choice, failures are not proofs afterwards
+    let mut guessed = false;
     for pi in 0..d.len() {
-        debug_assert_eq!(item_sum(d) as i32, sd);
+        debug_assert_eq!(item_sum(d), sd);
         // Check if total volume fits
         if sd > 0 {
-            return false;
+            return FitResult::failed(!guessed);
         }
         // pi points to a negative number -> forget it and remove from the sum
         if d[pi] < 0 {
-            sd -= (pi as i32) * d[pi];
+            sd -= (pi as i64) * d[pi];
             d[pi] = 0;
         }
         // pi points to items in need of packing -> resolve with best fit
         while d[pi] > 0 {
-            debug_assert!(item_sum(&d) as i32 == sd);
+            debug_assert!(item_sum(d) == sd);
             // Find next negative count, return false if all counts are non-neg
             while d[ni] >= 0 {
                 ni += 1;
                 if ni >= d.len() {
                     assert!(sd > 0);
-                    return false;
+                    return FitResult::failed(!guessed);
                 }
             }
             debug_assert!(ni > pi);
             // How many of pi pieces fit into a ni slot
-            let moved = min(d[pi], (ni as i32) / (pi as i32));
+            let moved = min(d[pi], (ni as i64) / (pi as i64));
             debug_assert!(moved > 0);
             // Remaining size of the ni slot
-            let rem = (ni as i32) - moved * (pi as i32);
+            let rem = (ni as i64) - moved * (pi as i64);
+            guessed = true;
+            log.record(pi, ni, moved as usize);
             d[ni] += 1;
             d[pi] -= moved;
-            if rem > pi as i32 {
+            if rem > pi as i64 {
                 // Remainder is larger than pi, remember it
                 d[rem as usize] -= 1;
                 ni = min(rem as usize, ni);
@@ -75,5 +92,5 @@ pub fn fits_into_bestfit_internal(d: &mut [i32], sd: i32, trim_upper: bool) -> b
         }
     }
     assert!(sd == 0);
-    return true;
+    FitResult::Fits
 }
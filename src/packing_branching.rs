@@ -1,6 +1,5 @@
 use crate::packing_bestfit::fits_into_bestfit_internal;
-use crate::packing_common::{item_sum, prep_diff};
-use crate::C;
+use crate::packing_common::{bounds_may_fit, item_sum, prep_diff, Count, FitResult, MoveLog};
 use std::cmp::min;
 
 /// Check if "items" `a` fit into "bins" `b` via best-fit matching.
@@ -9,7 +8,7 @@ use std::cmp::min;
 ///
 /// This works by substracting `d=a-b` and then trying to eliminate all
 /// positive numbers in `d` from smallest indices up.
-/// ```
+/// ```text
 /// a-b = ....+.+...-...-..+..---.++.-....
 ///           ^     ^                    
 ///           pi    ni
@@ -19,36 +18,131 @@ use std::cmp::min;
 /// are irellevant and thus erased. `sd` track the sum of the item
 /// sizes and is updated by forgotten negative bins, so we can stop if
 /// it is ever positive.
-pub fn fits_into_branching(a: &[C], b: &[C], branchings: usize) -> bool {
+///
+/// The lower bounds of `bounds_may_fit` are checked first.
+/// Returns `DoesNotFit` when all the branches were explored and refuted,
+/// `Unknown` when the branching budget was exhausted before that.
+///
+/// # Panics
+///
+/// May panic if `a[0] != 0` (items of size 0 are not allowed), validate untrusted counts with `check_counts`.
+pub fn fits_into_branching<T: Count>(a: &[T], b: &[T], branchings: usize) -> FitResult {
+    fits_into_branching_log(a, b, branchings, &mut ())
+}
+
+/// Same as `fits_into_branching`, recording the moves of the found packing into `log`
+pub fn fits_into_branching_log<T: Count, L: MoveLog>(
+    a: &[T],
+    b: &[T],
+    branchings: usize,
+    log: &mut L,
+) -> FitResult {
+    if !bounds_may_fit(a, b) {
+        return FitResult::DoesNotFit;
+    }
     let mut d = prep_diff(a, b);
-    let sd = item_sum(&d) as i32;
+    let sd = item_sum(&d);
     if branchings <= 1 {
-        fits_into_bestfit_internal(&mut d, sd, true)
+        fits_into_bestfit_internal(&mut d, sd, true, log)
     } else {
-        fits_into_branching_internal(&mut d, sd, branchings)
+        fits_into_branching_internal(&mut d, sd, branchings, log)
+    }
+}
+
+/// A node of the search in `fits_into_branching_internal`: the difference vector `d` (normalized),
+/// the branches `negs` (bins to put the largest item `hp` into) with the next one to try,
+/// the remaining budget `brs` split into `part` per branch, and whether all the branches are refuted so far
+struct Frame {
+    d: Vec<i64>,
+    sd: i64,
+    hp: usize,
+    negs: Vec<usize>,
+    next: usize,
+    part: usize,
+    brs: usize,
+    proven: bool,
+    /// Log mark before the move of the current branch
+    mark: usize,
+}
+
+/// The branches are explored depth-first with an explicit stack, as the search goes one level deeper
+/// for every item placed (also when there is only one branch), which could overflow the call stack.
+/// `d` may be edited in any way. On failure, `log` is left as it was.
+pub fn fits_into_branching_internal<L: MoveLog>(
+    d: &mut [i64],
+    sd: i64,
+    branchings: usize,
+    log: &mut L,
+) -> FitResult {
+    let mut stack = match branching_node(d, sd, branchings, log) {
+        Ok(r) => return r,
+        Err(f) => vec![f],
+    };
+    // Result of the last explored branch of the top frame
+    let mut child: Option<FitResult> = None;
+    while let Some(f) = stack.last_mut() {
+        if let Some(r) = child.take() {
+            match r {
+                FitResult::Fits => return FitResult::Fits,
+                FitResult::DoesNotFit => {}
+                FitResult::Unknown => f.proven = false,
+            }
+            log.rewind(f.mark);
+            f.brs -= min(f.brs, f.part);
+            f.next += 1;
+        }
+        if f.next == f.negs.len() {
+            child = Some(FitResult::failed(f.proven));
+            stack.pop();
+            continue;
+        }
+        let (hp, neg) = (f.hp, f.negs[f.next]);
+        let mut d2 = f.d.clone();
+        d2[neg] += 1;
+        d2[hp] -= 1;
+        d2[neg - hp] -= 1;
+        debug_assert_eq!(item_sum(&d2), f.sd);
+        f.mark = log.mark();
+        log.record(hp, neg, 1);
+        let (sd, budget) = (f.sd, min(f.brs, f.part));
+        match branching_node(&mut d2, sd, budget, log) {
+            Ok(r) => child = Some(r),
+            Err(f) => stack.push(f),
+        }
     }
+    child.unwrap()
 }
 
-/// Recursive; `d` may be edited in any way.
-/// Warning: recurses not just for branchings but also when an unique `(hpos->hneg)` update is found.
-pub fn fits_into_branching_internal(d: &mut [i32], sd: i32, branchings: usize) -> bool {
+/// Decide the node `d` with the given budget right away (best-fit when `branchings <= 1`),
+/// or return its branches as a `Frame`
+fn branching_node<L: MoveLog>(
+    d: &mut [i64],
+    sd: i64,
+    branchings: usize,
+    log: &mut L,
+) -> Result<FitResult, Frame> {
     if branchings <= 1 {
         // trim_upper_bins is already done if at least one branching happened
-        return fits_into_bestfit_internal(d, sd, false);
+        let mark = log.mark();
+        let r = fits_into_bestfit_internal(d, sd, false, log);
+        if !r.fits() {
+            log.rewind(mark);
+        }
+        return Ok(r);
     }
 
     let mut sd = sd;
     loop {
-        // filter lower negatives and check sd <= 0
-        let lpos = d.iter().rposition(|x| *x > 0);
-        let lneg = d.iter().rposition(|x| *x < 0);
+        // filter lower negatives (under the lowest positive) and check sd <= 0
+        let lpos = d.iter().position(|x| *x > 0);
+        let lneg = d.iter().position(|x| *x < 0);
         match (lpos, lneg) {
             (Some(lp), Some(ln)) if lp > ln => {
-                sd -= d[ln] * ln as i32;
+                sd -= d[ln] * ln as i64;
                 d[ln] = 0;
-                debug_assert_eq!(item_sum(&d) as i32, sd);
+                debug_assert_eq!(item_sum(d), sd);
                 if sd > 0 {
-                    return false;
+                    return Ok(FitResult::DoesNotFit);
                 }
                 // Another loop to check for more low negs
                 continue;
@@ -59,42 +153,40 @@ pub fn fits_into_branching_internal(d: &mut [i32], sd: i32, branchings: usize) -
         // find upper non-zero bins
         let hpos = d.iter().rposition(|x| *x > 0);
         let hneg = d.iter().rposition(|x| *x < 0);
-        match (hpos, hneg) {
+        return match (hpos, hneg) {
             // Trivially fits, all bins non-positive
-            (None, _) => return true,
+            (None, _) => Ok(FitResult::Fits),
             // Only non-zero bin is positive, fail
-            (Some(_), None) => return false,
+            (Some(_), None) => Ok(FitResult::DoesNotFit),
             // Largest non-zero bin positive, fail
-            (Some(hp), Some(hn)) if hp > hn => return false,
+            (Some(hp), Some(hn)) if hp > hn => Ok(FitResult::DoesNotFit),
             // Largest non-zero bin negative, carry on
             (Some(hp), Some(hn)) => {
                 assert!(hp < hn);
                 // collect all negative values between hp and hn (incl.)
-                let negs: Vec<usize> = d[hp..]
+                let mut negs: Vec<usize> = d[hp..]
                     .iter()
                     .enumerate()
                     .filter_map(|(i, x)| if *x < 0 { Some(i + hp) } else { None })
                     .collect();
-                debug_assert!(negs.len() > 0);
+                debug_assert!(!negs.is_empty());
                 debug_assert!(branchings > 1);
+                // Refuted only if all the branches are explored and refuted
+                let proven = branchings >= negs.len();
                 // limit to how many branches we have, lower negs first
-                let negs_b = &negs[..min(branchings, negs.len())];
-                let part = (branchings + negs_b.len() - 1) / negs_b.len();
-                let mut brs = branchings;
-                for neg in negs_b {
-                    debug_assert!(*neg > hp);
-                    let mut d2: Vec<i32> = d.into();
-                    d2[*neg] += 1;
-                    d2[hp] -= 1;
-                    d2[*neg - hp] -= 1;
-                    debug_assert_eq!(item_sum(&d2) as i32, sd);
-                    if fits_into_branching_internal(&mut d2, sd, min(brs, part)) {
-                        return true;
-                    }
-                    brs -= min(brs, part);
-                }
-                return false;
+                negs.truncate(branchings);
+                Err(Frame {
+                    d: d.to_vec(),
+                    sd,
+                    hp,
+                    part: branchings.div_ceil(negs.len()),
+                    negs,
+                    next: 0,
+                    brs: branchings,
+                    proven,
+                    mark: 0,
+                })
             }
-        }
+        };
     }
 }
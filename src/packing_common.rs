@@ -1,30 +1,305 @@
-use crate::C;
+use std::cell::RefCell;
 use std::cmp::max;
 use std::cmp::min;
+use std::fmt::Debug;
 
-/// Conver a count vector into a list of item sizes, decreasing
-pub fn counts_to_sizes(counts: &[C]) -> Vec<C> {
-    assert!(counts.len() < C::MAX as usize);
-    assert!(counts.len() == 0 || counts[0] == 0);
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Types of the item counts (and of the item sizes, when given as a list): `u8`, `u16` and `u32`.
+/// Sealed, as the storage relies on them being plain integers (e.g. in memory-mapped files).
+pub trait Count:
+    sealed::Sealed + Copy + Default + Ord + Debug + Send + Sync + Into<i64> + TryFrom<usize> + 'static
+{
+    /// The maximal value, as `usize`
+    const MAX_USIZE: usize;
+    /// Size in bytes
+    const BYTES: usize;
+
+    fn as_usize(self) -> usize;
+
+    /// Append the little-endian bytes to `out`
+    fn write_le(self, out: &mut Vec<u8>);
+
+    /// Read from little-endian `bytes` (of length `BYTES`)
+    fn read_le(bytes: &[u8]) -> Self;
+
+    /// Panics if `v` does not fit
+    fn from_usize(v: usize) -> Self {
+        Self::try_from(v).unwrap_or_else(|_| panic!("Value {} out of range", v))
+    }
+}
+
+macro_rules! impl_count {
+    ($($t:ty),*) => {$(
+        impl sealed::Sealed for $t {}
+
+        impl Count for $t {
+            const MAX_USIZE: usize = <$t>::MAX as usize;
+            const BYTES: usize = std::mem::size_of::<$t>();
+
+            #[inline]
+            fn as_usize(self) -> usize {
+                self as usize
+            }
+
+            #[inline]
+            fn write_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            #[inline]
+            fn read_le(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    )*};
+}
+
+impl_count!(u8, u16, u32);
+
+/// Result of a packing check: `Fits` and `DoesNotFit` are proven,
+/// `Unknown` means that a heuristic gave up without finding a packing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitResult {
+    Fits,
+    DoesNotFit,
+    Unknown,
+}
+
+impl FitResult {
+    /// Only a found packing counts as fitting
+    pub fn fits(self) -> bool {
+        self == FitResult::Fits
+    }
+
+    /// `DoesNotFit` for proven failures, `Unknown` otherwise
+    pub fn failed(proven: bool) -> Self {
+        if proven {
+            FitResult::DoesNotFit
+        } else {
+            FitResult::Unknown
+        }
+    }
+}
+
+/// A move done while reducing a difference vector: `count` items of size `item`
+/// were put into one bin slot with the remaining size `slot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub item: usize,
+    pub slot: usize,
+    pub count: usize,
+}
+
+/// Records the moves done by the packing algorithms (to reconstruct the packing).
+/// The no-op `()` is used when only the result is needed.
+pub trait MoveLog {
+    fn record(&mut self, item: usize, slot: usize, count: usize);
+    /// Current position in the log, for `rewind` when backtracking
+    fn mark(&self) -> usize;
+    /// Forget all the moves after the given `mark`
+    fn rewind(&mut self, mark: usize);
+}
+
+impl MoveLog for () {
+    #[inline]
+    fn record(&mut self, _item: usize, _slot: usize, _count: usize) {}
+    #[inline]
+    fn mark(&self) -> usize {
+        0
+    }
+    #[inline]
+    fn rewind(&mut self, _mark: usize) {}
+}
+
+impl MoveLog for Vec<Move> {
+    fn record(&mut self, item: usize, slot: usize, count: usize) {
+        self.push(Move { item, slot, count });
+    }
+    fn mark(&self) -> usize {
+        self.len()
+    }
+    fn rewind(&mut self, mark: usize) {
+        self.truncate(mark);
+    }
+}
+
+/// Errors in item sets outside of the representable (or allowed) range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeError {
+    /// An item of size `size` (at `index`) is over `max_size`
+    SizeTooLarge {
+        index: usize,
+        size: usize,
+        max_size: usize,
+    },
+    /// There are `count` items of size `size`, over `max_count` of the count type
+    CountOverflow {
+        size: usize,
+        count: usize,
+        max_count: usize,
+    },
+    /// An item of size 0 (at `index`) in a list of sizes
+    ZeroSize { index: usize },
+    /// A count vector with `count` items of size 0 (at index 0)
+    ZeroSizeCount { count: usize },
+}
+
+impl RangeError {
+    /// Whether the error is a value over a limit (as opposed to an invalid value)
+    pub fn is_overflow(&self) -> bool {
+        matches!(
+            self,
+            RangeError::SizeTooLarge { .. } | RangeError::CountOverflow { .. }
+        )
+    }
+}
+
+impl std::error::Error for RangeError {}
+
+impl std::fmt::Display for RangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RangeError::SizeTooLarge {
+                index,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "Item size {} at index {} is over the maximum size {}",
+                size, index, max_size
+            ),
+            RangeError::CountOverflow {
+                size,
+                count,
+                max_count,
+            } => write!(
+                f,
+                "Count {} of items of size {} is over the maximum count {}",
+                count, size, max_count
+            ),
+            RangeError::ZeroSize { index } => {
+                write!(f, "Item size 0 at index {} is not allowed", index)
+            }
+            RangeError::ZeroSizeCount { count } => write!(
+                f,
+                "Count {} at index 0 must be 0 (items of size 0 are not allowed)",
+                count
+            ),
+        }
+    }
+}
+
+/// Convert a count vector into a list of item sizes, decreasing
+///
+/// # Panics
+///
+/// Panics if `counts[0] != 0` (items of size 0 are not allowed), validate untrusted counts with `check_counts`.
+pub fn counts_to_sizes<T: Count>(counts: &[T]) -> Vec<usize> {
+    assert!(counts.is_empty() || counts[0] == T::default());
     let mut res = Vec::new();
     for (i, &val) in counts.iter().enumerate().rev() {
-        for _ in 0..val {
-            res.push(i as u8);
+        for _ in 0..val.as_usize() {
+            res.push(i);
         }
     }
     res
 }
 
-/// Conver a count list of item sizes into a count vector
-pub fn sizes_to_counts(sizes: &[C]) -> Vec<C> {
-    assert!(sizes.len() < C::MAX as usize);
-    let m = *sizes.iter().max().unwrap_or(&0);
-    let mut res = vec![0; m as usize + 1];
+/// Convert a list of item sizes into a count vector
+///
+/// # Panics
+///
+/// Panics on a size 0 or if a count overflows `T`, use `try_sizes_to_counts` for untrusted sizes.
+pub fn sizes_to_counts<T: Count>(sizes: &[T]) -> Vec<T> {
+    let ss: Vec<usize> = sizes.iter().map(|s| s.as_usize()).collect();
+    match try_sizes_to_counts(&ss, usize::MAX) {
+        Ok(cs) => cs,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Conver a count list of item sizes into a count vector, checking that all sizes
+/// are at most `max_size` and that the counts fit into `T`
+pub fn try_sizes_to_counts<T: Count>(
+    sizes: &[usize],
+    max_size: usize,
+) -> Result<Vec<T>, RangeError> {
+    check_sizes_range(sizes, max_size)?;
+    let m = sizes.iter().max().map_or(0, |x| *x);
+    let mut res = vec![0usize; m + 1];
     for &s in sizes {
-        assert!(s != 0);
-        res[s as usize] += 1;
+        res[s] += 1;
+    }
+    if let Some((size, &count)) = res.iter().enumerate().find(|(_, c)| **c > T::MAX_USIZE) {
+        return Err(RangeError::CountOverflow {
+            size,
+            count,
+            max_count: T::MAX_USIZE,
+        });
+    }
+    Ok(res.into_iter().map(T::from_usize).collect())
+}
+
+/// Check that all the item sizes are non-zero and at most `max_size`
+pub fn check_sizes_range(sizes: &[usize], max_size: usize) -> Result<(), RangeError> {
+    for (index, &size) in sizes.iter().enumerate() {
+        if size == 0 {
+            return Err(RangeError::ZeroSize { index });
+        }
+        if size > max_size {
+            return Err(RangeError::SizeTooLarge {
+                index,
+                size,
+                max_size,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Convert counts given as `usize` into a count vector, checking that there are no items
+/// of size 0, all sizes are at most `max_size` and the counts fit into `T`
+pub fn try_counts<T: Count>(counts: &[usize], max_size: usize) -> Result<Vec<T>, RangeError> {
+    if let Some(&count) = counts.first().filter(|c| **c != 0) {
+        return Err(RangeError::ZeroSizeCount { count });
+    }
+    check_counts_range(counts, max_size)?;
+    if let Some((size, &count)) = counts.iter().enumerate().find(|(_, c)| **c > T::MAX_USIZE) {
+        return Err(RangeError::CountOverflow {
+            size,
+            count,
+            max_count: T::MAX_USIZE,
+        });
+    }
+    Ok(counts.iter().map(|c| T::from_usize(*c)).collect())
+}
+
+/// Check that the count vector has no items of size 0 and only items of size at most `max_size`
+pub fn check_counts<T: Count>(counts: &[T], max_size: usize) -> Result<(), RangeError> {
+    if let Some(&count) = counts.first().filter(|c| **c != T::default()) {
+        return Err(RangeError::ZeroSizeCount {
+            count: count.as_usize(),
+        });
+    }
+    check_counts_range(counts, max_size)
+}
+
+/// Check that the count vector only has items of size at most `max_size`
+pub fn check_counts_range<T: Default + PartialEq>(
+    counts: &[T],
+    max_size: usize,
+) -> Result<(), RangeError> {
+    match counts.iter().rposition(|c| *c != T::default()) {
+        Some(size) if size > max_size => Err(RangeError::SizeTooLarge {
+            index: size,
+            size,
+            max_size,
+        }),
+        _ => Ok(()),
     }
-    res
 }
 
 /// Given counts of individual items, return the sum of their sizes
@@ -37,29 +312,88 @@ pub fn item_sum<T: Into<i64> + Copy>(counts: &[T]) -> i64 {
 }
 
 /// Create a difference vector of a-b
-pub fn prep_diff(a: &[C], b: &[C]) -> Vec<i32> {
-    let mut d: Vec<i32> = vec![0; max(a.len(), b.len())];
-    for i in 0..d.len() {
-        d[i] += *a.get(i).unwrap_or(&0) as i32;
-        d[i] -= *b.get(i).unwrap_or(&0) as i32;
+pub fn prep_diff<T: Count>(a: &[T], b: &[T]) -> Vec<i64> {
+    let mut d: Vec<i64> = vec![0; max(a.len(), b.len())];
+    for (i, di) in d.iter_mut().enumerate() {
+        *di += a.get(i).map_or(0, |x| (*x).into());
+        *di -= b.get(i).map_or(0, |x| (*x).into());
     }
     d
 }
 
+/// Check fast necessary conditions for fitting "items" `a` into "bins" `b`,
+/// returns `false` if the packing is proven impossible.
+///
+/// These are L1/L2-style lower bounds generalized to bins of different sizes.
+/// For every item size `s`, the items of size at least `s`:
+/// * must have total volume at most the total size of the bins of size at least `s`
+///   (with the smallest `s`, this is a stronger version of the volume bound),
+/// * must be at most as many as `sum(floor(B/s))` over all bins `B` (e.g. the items larger
+///   than half of any bin need separate bins).
+///
+/// The latter is computed as `sum(bins_ge[k*s])` over `k>=1`, so all checks take `O(n log n)`.
+/// The plain volume bound is checked first; `bins_ge` is then filled in a single pass from the top,
+/// in a per-thread buffer, as this is called on every pair.
+pub fn bounds_may_fit<T: Count>(a: &[T], b: &[T]) -> bool {
+    if item_sum(a) > item_sum(b) {
+        return false;
+    }
+    let Some(smallest) = (1..a.len()).find(|&s| a[s] != T::default()) else {
+        return true;
+    };
+    let n = max(a.len(), b.len());
+    BINS_GE.with(|buf| {
+        // Counts of bins of size >= s, filled for s from the top down to `smallest`
+        let mut bins_ge = buf.borrow_mut();
+        bins_ge.clear();
+        bins_ge.resize(n + 1, 0);
+        let mut bins_vol_ge = 0i64;
+        let mut items_ge = 0i64;
+        let mut items_vol_ge = 0i64;
+        for s in (smallest..n).rev() {
+            let c: i64 = b.get(s).map_or(0, |x| (*x).into());
+            bins_ge[s] = bins_ge[s + 1] + c;
+            bins_vol_ge += c * s as i64;
+            let c: i64 = a.get(s).map_or(0, |x| (*x).into());
+            if c == 0 {
+                continue;
+            }
+            items_ge += c;
+            items_vol_ge += c * s as i64;
+            if items_vol_ge > bins_vol_ge {
+                return false;
+            }
+            let slots: i64 = (s..n).step_by(s).map(|ks| bins_ge[ks]).sum();
+            if items_ge > slots {
+                return false;
+            }
+        }
+        true
+    })
+}
+
+thread_local! {
+    /// Buffer of `bounds_may_fit`
+    static BINS_GE: RefCell<Vec<i64>> = const { RefCell::new(Vec::new()) };
+}
+
 /// Edit `d` and `sd` to reflect necessary matchings in upper bins,
 /// returns whether matching may still be possible.
+/// All the edits are forced, so returning `false` is a proof that `d` can't be matched.
 ///
 /// Fails if upper non-zero bin is positive (can't fit anywhere).
 /// If top two bins are (neg, pos), cancels the positive bin with the negative one.
 /// Does not change the `item_sum` of `d`.
-pub fn trim_upper_bins(d: &mut [i32]) -> bool {
+#[cfg(test)]
+pub fn trim_upper_bins(d: &mut [i64]) -> bool {
     let mut ni: usize = 0;
-    trim_upper_bins_ni(d, &mut ni)
+    trim_upper_bins_ni(d, &mut ni, &mut ())
 }
 
 /// Same as `trim_upper_bins` but updates `ni` to point to the smallest-index neg value
-pub fn trim_upper_bins_ni(d: &mut [i32], ni: &mut usize) -> bool {
-    if d.len() == 0 {
+/// and records the moves into `log`
+pub fn trim_upper_bins_ni<L: MoveLog>(d: &mut [i64], ni: &mut usize, log: &mut L) -> bool {
+    if d.is_empty() {
         return true;
     }
     loop {
@@ -85,6 +419,7 @@ pub fn trim_upper_bins_ni(d: &mut [i32], ni: &mut usize) -> bool {
                 d[hp] -= 1;
                 d[hn - hp] -= 1;
                 *ni = min(*ni, hn - hp);
+                log.record(hp, hn, 1);
             }
         }
     }
@@ -0,0 +1,143 @@
+use crate::packing_bestfit::fits_into_bestfit_internal;
+use crate::packing_common::{
+    bounds_may_fit, item_sum, prep_diff, trim_upper_bins_ni, Count, FitResult, MoveLog,
+};
+use std::collections::HashSet;
+
+/// Check if "items" `a` fit into "bins" `b` via complete exhaustive search.
+/// Both `a` and `b` are given as counts of items of every size.
+///
+/// Unlike `fits_into_branching`, the answer is always exact (never `Unknown`). The search works on
+/// the difference vector `d=a-b` (as the other algorithms do) and always resolves the
+/// largest positive count `hp` by branching on *all* the distinct negative counts above it:
+/// ```text
+/// a-b = ....+.+...-...+..-.--...-...
+///                     ^  ^ ^^   ^
+///                     hp branches
+/// ```
+/// Bins of the same remaining size are interchangeable, so this is complete.
+/// Negative counts under the lowest positive count are forgotten (no item fits there),
+/// and the search fails whenever the remaining volume `sd` is positive.
+/// Failed difference vectors are remembered, as many branch orders lead to the same state.
+/// The lower bounds of `bounds_may_fit` and best-fit are tried first, quickly deciding the easy instances.
+///
+/// # Panics
+///
+/// May panic if `a[0] != 0` (items of size 0 are not allowed), validate untrusted counts with `check_counts`.
+pub fn fits_into_exact<T: Count>(a: &[T], b: &[T]) -> FitResult {
+    fits_into_exact_log(a, b, &mut ())
+}
+
+/// Same as `fits_into_exact`, recording the moves of the found packing into `log`
+pub fn fits_into_exact_log<T: Count, L: MoveLog>(a: &[T], b: &[T], log: &mut L) -> FitResult {
+    if !bounds_may_fit(a, b) {
+        return FitResult::DoesNotFit;
+    }
+    let mut d = prep_diff(a, b);
+    let sd = item_sum(&d);
+    if !trim_upper_bins_ni(&mut d, &mut 0, log) {
+        return FitResult::DoesNotFit;
+    }
+    debug_assert_eq!(item_sum(&d), sd);
+    let mark = log.mark();
+    match fits_into_bestfit_internal(&mut d.clone(), sd, false, log) {
+        FitResult::Unknown => log.rewind(mark),
+        r => return r,
+    }
+    let mut failed = HashSet::new();
+    if fits_into_exact_internal(&mut d, sd, &mut failed, log) {
+        FitResult::Fits
+    } else {
+        FitResult::DoesNotFit
+    }
+}
+
+/// A node of the search in `fits_into_exact_internal`: the normalized difference vector `d`
+/// with the largest item `hp` and the next bin above it to try
+struct Frame {
+    d: Vec<i64>,
+    sd: i64,
+    hp: usize,
+    next: usize,
+    /// Log mark before the move leading to this node
+    mark: usize,
+}
+
+/// Depth-first search with an explicit stack, as it goes one level deeper for every item placed
+/// (which could overflow the call stack); `d` may be edited in any way, `failed` collects the refuted states.
+/// On failure, `log` is left as it was.
+pub fn fits_into_exact_internal<L: MoveLog>(
+    d: &mut [i64],
+    sd: i64,
+    failed: &mut HashSet<Vec<i64>>,
+    log: &mut L,
+) -> bool {
+    let mut stack = match exact_node(d, sd, failed) {
+        Ok(fits) => return fits,
+        Err((hp, sd)) => vec![Frame {
+            d: d.to_vec(),
+            sd,
+            hp,
+            next: hp + 1,
+            mark: log.mark(),
+        }],
+    };
+    while let Some(f) = stack.last_mut() {
+        let Some(neg) = (f.next..f.d.len()).find(|&i| f.d[i] < 0) else {
+            let f = stack.pop().unwrap();
+            log.rewind(f.mark);
+            failed.insert(f.d);
+            continue;
+        };
+        f.next = neg + 1;
+        let hp = f.hp;
+        let mut d2 = f.d.clone();
+        d2[neg] += 1;
+        d2[hp] -= 1;
+        d2[neg - hp] -= 1;
+        debug_assert_eq!(item_sum(&d2), f.sd);
+        let sd = f.sd;
+        let mark = log.mark();
+        log.record(hp, neg, 1);
+        match exact_node(&mut d2, sd, failed) {
+            Ok(true) => return true,
+            Ok(false) => log.rewind(mark),
+            Err((hp, sd)) => stack.push(Frame {
+                d: d2,
+                sd,
+                hp,
+                next: hp + 1,
+                mark,
+            }),
+        }
+    }
+    false
+}
+
+/// Normalize the node `d` (forgetting the negatives under the lowest positive count) and decide it
+/// right away if possible, otherwise return the largest item `hp` to branch on and the new `sd`
+fn exact_node(d: &mut [i64], sd: i64, failed: &HashSet<Vec<i64>>) -> Result<bool, (usize, i64)> {
+    // forget negatives under the lowest positive count
+    let lp = match d.iter().position(|x| *x > 0) {
+        None => return Ok(true),
+        Some(lp) => lp,
+    };
+    let mut sd = sd;
+    for (i, di) in d.iter_mut().enumerate().take(lp) {
+        if *di < 0 {
+            sd -= (i as i64) * *di;
+            *di = 0;
+        }
+    }
+    debug_assert_eq!(item_sum(d), sd);
+    if sd > 0 {
+        return Ok(false);
+    }
+
+    // the largest item has to go into one of the larger bins
+    let hp = d.iter().rposition(|x| *x > 0).unwrap();
+    if !d[hp..].iter().any(|x| *x < 0) || failed.contains(d) {
+        return Ok(false);
+    }
+    Err((hp, sd))
+}
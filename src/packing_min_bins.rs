@@ -0,0 +1,201 @@
+use crate::packing_common::{check_counts, item_sum, Count, RangeError};
+use crate::packing_witness::{packing_into_exact, Packing};
+use std::cmp::max;
+use std::collections::BTreeMap;
+
+/// The largest capacity for the exact search of `min_bins`
+pub const MAX_EXACT_CAPACITY: usize = 1 << 20;
+
+/// Solution of the bin packing problem with identical bins, see `min_bins`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinBins {
+    /// Number of bins of the best packing found
+    pub bins: usize,
+    /// Proven lower bound on the number of bins, equal to `bins` when the packing is optimal
+    pub lower_bound: usize,
+    /// The L1 (volume) lower bound
+    pub l1: usize,
+    /// The L2 (Martello-Toth) lower bound
+    pub l2: usize,
+    /// Number of bins used by first-fit decreasing
+    pub ffd: usize,
+    /// Number of bins used by best-fit decreasing
+    pub bfd: usize,
+    /// The best packing found: `bins` bins of size `capacity`
+    pub packing: Packing,
+}
+
+impl MinBins {
+    /// Whether `bins` is proven to be the minimum
+    pub fn is_optimal(&self) -> bool {
+        self.bins == self.lower_bound
+    }
+}
+
+/// Minimum number of bins of size `capacity` holding the items given by `counts` (classic bin packing).
+///
+/// The upper bounds are first-fit decreasing and best-fit decreasing, the lower bounds L1 and L2.
+/// When they disagree and `exact` is set, every number of bins `k` from the lower bound up is tried:
+/// the items fit into `k` bins iff they fit (by `fits_into_exact`) into the count vector with `k`
+/// bins of size `capacity`, so the first `k` that fits is optimal. Without `exact`, the better
+/// heuristic packing is returned (optimal if it meets the lower bound).
+///
+/// The exact search is exponential in the worst case and uses count vectors of length `capacity + 1`,
+/// so it is skipped for capacities over `MAX_EXACT_CAPACITY` (the result is then as without `exact`).
+/// Fails if any item is larger than `capacity`.
+pub fn min_bins<T: Count>(
+    counts: &[T],
+    capacity: usize,
+    exact: bool,
+) -> Result<MinBins, RangeError> {
+    check_counts(counts, capacity)?;
+    let ffd = first_fit_decreasing(counts, capacity);
+    let bfd = best_fit_decreasing(counts, capacity);
+    let (l1, l2) = (
+        lower_bound_l1(counts, capacity),
+        lower_bound_l2(counts, capacity),
+    );
+    let mut res = MinBins {
+        bins: 0,
+        lower_bound: max(l1, l2),
+        l1,
+        l2,
+        ffd: ffd.len(),
+        bfd: bfd.len(),
+        packing: if bfd.len() <= ffd.len() { bfd } else { ffd },
+    };
+    res.bins = res.packing.len();
+    if !exact || res.is_optimal() || capacity > MAX_EXACT_CAPACITY {
+        return Ok(res);
+    }
+
+    let a: Vec<u32> = counts.iter().map(|c| c.as_usize() as u32).collect();
+    let mut b = vec![0u32; capacity + 1];
+    while res.lower_bound < res.bins {
+        b[capacity] = u32::try_from(res.lower_bound).expect("Too many bins for the exact search");
+        match packing_into_exact(&a, &b) {
+            Some(packing) => {
+                res.packing = packing;
+                res.bins = res.lower_bound;
+            }
+            None => res.lower_bound += 1,
+        }
+    }
+    Ok(res)
+}
+
+/// The L1 lower bound: the total volume of the items over `capacity`, rounded up.
+/// The items must be at most `capacity` (as in all the bounds and heuristics here).
+pub fn lower_bound_l1<T: Count>(counts: &[T], capacity: usize) -> usize {
+    let volume = item_sum(counts) as usize;
+    if volume == 0 {
+        0
+    } else {
+        volume.div_ceil(capacity)
+    }
+}
+
+/// The L2 lower bound of Martello and Toth, at least as strong as L1.
+///
+/// For a threshold `alpha <= capacity/2`, the items larger than `capacity - alpha` (J1) and
+/// the items larger than `capacity/2` (J2) need a bin each, with no items of size at least `alpha` (J3)
+/// fitting in J1 bins. The J3 items then need additional bins for their volume over the free space
+/// of the J2 bins. The maximum over `alpha` of the J1, J2 and additional bins is the bound;
+/// it is enough to try `alpha = 0` and the item sizes up to `capacity/2`.
+pub fn lower_bound_l2<T: Count>(counts: &[T], capacity: usize) -> usize {
+    let w = capacity as i64;
+    let half = capacity / 2;
+    // Counts and volumes of the items of sizes `< s`
+    let mut cnt = vec![0i64; counts.len() + 1];
+    let mut vol = vec![0i64; counts.len() + 1];
+    for (s, c) in counts.iter().enumerate() {
+        let c: i64 = (*c).into();
+        cnt[s + 1] = cnt[s] + c;
+        vol[s + 1] = vol[s] + c * s as i64;
+    }
+    // Count and volume of the items with sizes in `lo..hi`
+    let range = |lo: usize, hi: usize| {
+        let (lo, hi) = (lo.min(counts.len()), hi.min(counts.len()));
+        if lo < hi {
+            (cnt[hi] - cnt[lo], vol[hi] - vol[lo])
+        } else {
+            (0, 0)
+        }
+    };
+    let alphas = (1..counts.len().min(half + 1)).filter(|&s| counts[s] != T::default());
+    let mut best = 0;
+    for alpha in std::iter::once(0).chain(alphas) {
+        let (n1, _) = range((capacity - alpha).saturating_add(1), usize::MAX);
+        let (n2, v2) = range(half + 1, (capacity - alpha).saturating_add(1));
+        let (_, v3) = range(alpha, half + 1);
+        let over = v3 - (n2 * w - v2);
+        let extra = if over > 0 { (over + w - 1) / w } else { 0 };
+        best = max(best, n1 + n2 + extra);
+    }
+    best as usize
+}
+
+/// First-fit decreasing: every item, largest first, goes into the first bin with enough space.
+///
+/// The remaining space of the bins is kept in a tournament tree (maximum of the subtree),
+/// so the first fitting bin is found in `O(log n)`.
+pub fn first_fit_decreasing<T: Count>(counts: &[T], capacity: usize) -> Packing {
+    let n: usize = counts.iter().map(|c| c.as_usize()).sum();
+    let leaves = n.next_power_of_two();
+    // The leaves are the bins, unused ones with the full capacity; there are never more bins than items
+    let mut tree = vec![capacity; 2 * leaves];
+    let mut bins: Packing = Vec::new();
+    for (size, c) in counts.iter().enumerate().rev() {
+        for _ in 0..c.as_usize() {
+            let mut i = 1;
+            while i < leaves {
+                i = if tree[2 * i] >= size {
+                    2 * i
+                } else {
+                    2 * i + 1
+                };
+            }
+            if i - leaves == bins.len() {
+                bins.push((capacity, Vec::new()));
+            }
+            bins[i - leaves].1.push(size);
+            tree[i] -= size;
+            while i > 1 {
+                i /= 2;
+                tree[i] = max(tree[2 * i], tree[2 * i + 1]);
+            }
+        }
+    }
+    bins
+}
+
+/// Best-fit decreasing: every item, largest first, goes into the bin with the least
+/// remaining space that is enough, or a new bin.
+pub fn best_fit_decreasing<T: Count>(counts: &[T], capacity: usize) -> Packing {
+    let mut bins: Packing = Vec::new();
+    // Indices of the open bins by their remaining space
+    let mut free: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (size, c) in counts.iter().enumerate().rev() {
+        for _ in 0..c.as_usize() {
+            let (bin, rem) = match free.range(size..).next().map(|(rem, _)| *rem) {
+                Some(rem) => {
+                    let slots = free.get_mut(&rem).unwrap();
+                    let bin = slots.pop().unwrap();
+                    if slots.is_empty() {
+                        free.remove(&rem);
+                    }
+                    (bin, rem)
+                }
+                None => {
+                    bins.push((capacity, Vec::new()));
+                    (bins.len() - 1, capacity)
+                }
+            };
+            bins[bin].1.push(size);
+            if rem > size {
+                free.entry(rem - size).or_default().push(bin);
+            }
+        }
+    }
+    bins
+}
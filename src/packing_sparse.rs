@@ -0,0 +1,388 @@
+use crate::packing_common::{check_sizes_range, Count, FitResult, RangeError};
+use std::cmp::min;
+use std::collections::BTreeMap;
+
+/// Sparse item set: `(size, count)` pairs with increasing sizes and non-zero counts
+pub type SparseCounts<T> = Vec<(u32, T)>;
+
+/// Sparse difference vector: only the non-zero counts, by size
+pub type SparseDiff = BTreeMap<usize, i64>;
+
+/// Convert a count vector into a sparse item set
+pub fn dense_to_sparse<T: Count>(counts: &[T]) -> SparseCounts<T> {
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c != T::default())
+        .map(|(s, c)| (s as u32, *c))
+        .collect()
+}
+
+/// Convert a sparse item set into a count vector
+pub fn sparse_to_dense<T: Count>(counts: &[(u32, T)]) -> Vec<T> {
+    let mut res = vec![T::default(); counts.last().map_or(0, |(s, _)| *s as usize + 1)];
+    for (s, c) in counts {
+        res[*s as usize] = *c;
+    }
+    res
+}
+
+/// Create a sparse difference vector of a-b
+pub fn prep_diff_sparse<T: Count>(a: &[(u32, T)], b: &[(u32, T)]) -> SparseDiff {
+    let mut d = SparseDiff::new();
+    for (s, c) in a {
+        add(&mut d, *s as usize, (*c).into());
+    }
+    for (s, c) in b {
+        add(&mut d, *s as usize, -(*c).into());
+    }
+    d
+}
+
+/// Given a sparse difference vector, return the sum of the item sizes
+pub fn item_sum_sparse(d: &SparseDiff) -> i64 {
+    d.iter().map(|(s, v)| (*s as i64) * v).sum()
+}
+
+/// Add `v` to `d[i]`, keeping only the non-zero counts
+fn add(d: &mut SparseDiff, i: usize, v: i64) {
+    let e = d.entry(i).or_insert(0);
+    *e += v;
+    if *e == 0 {
+        d.remove(&i);
+    }
+}
+
+/// Same as `bounds_may_fit`, for sparse item sets, in `O(ka * kb)` for `ka` resp. `kb`
+/// distinct item resp. bin sizes.
+pub fn bounds_may_fit_sparse<T: Count>(a: &[(u32, T)], b: &[(u32, T)]) -> bool {
+    let mut items_ge = 0i64;
+    let mut items_vol_ge = 0i64;
+    for (s, c) in a.iter().rev() {
+        let (s, c) = (*s as i64, (*c).into());
+        if s == 0 || c == 0 {
+            continue;
+        }
+        items_ge += c;
+        items_vol_ge += c * s;
+        let mut bins_vol_ge = 0i64;
+        let mut slots = 0i64;
+        for (bs, bc) in b.iter().rev() {
+            let (bs, bc): (i64, i64) = (*bs as i64, (*bc).into());
+            if bs < s {
+                break;
+            }
+            bins_vol_ge += bc * bs;
+            slots += bc * (bs / s);
+        }
+        if items_vol_ge > bins_vol_ge || items_ge > slots {
+            return false;
+        }
+    }
+    true
+}
+
+/// Same as `trim_upper_bins`, for sparse difference vectors
+pub fn trim_upper_bins_sparse(d: &mut SparseDiff) -> bool {
+    loop {
+        let mut it = d.iter().rev().map(|(s, v)| (*s, *v));
+        let hn = match it.next() {
+            // Trivially fits
+            None => return true,
+            // Largest non-zero bin positive
+            Some((_, v)) if v > 0 => return false,
+            Some((hn, _)) => hn,
+        };
+        // The next non-zero bin below hn
+        let hp = match it.next() {
+            // Positive -> can only go into hn
+            Some((hp, v)) if v > 0 => hp,
+            // Negative -> unknown how to resolve the largest positive
+            Some(_) => return true,
+            // Only negative bins
+            None => return true,
+        };
+        // Put one hp into hn, (hn-hp) remains
+        add(d, hn, 1);
+        add(d, hp, -1);
+        add(d, hn - hp, -1);
+    }
+}
+
+/// Same as `fits_into_bestfit`, for sparse item sets.
+/// Iterates only over the non-zero counts and gives the same results.
+pub fn fits_into_bestfit_sparse<T: Count>(
+    a: &[(u32, T)],
+    b: &[(u32, T)],
+    trim_upper: bool,
+) -> FitResult {
+    let mut d = prep_diff_sparse(a, b);
+    let sd = item_sum_sparse(&d);
+    fits_into_bestfit_sparse_internal(&mut d, sd, trim_upper)
+}
+
+pub fn fits_into_bestfit_sparse_internal(
+    d: &mut SparseDiff,
+    sd: i64,
+    trim_upper: bool,
+) -> FitResult {
+    if trim_upper {
+        if !trim_upper_bins_sparse(d) {
+            return FitResult::DoesNotFit;
+        }
+        debug_assert_eq!(item_sum_sparse(d), sd);
+    }
+    let mut ni = 0;
+    let mut sd = sd;
+    // Set after the first (non-forced) best-fit choice, failures are not proofs afterwards
+    let mut guessed = false;
+    // Lowest index not yet processed
+    let mut next = 0;
+    while let Some((&pi, &v)) = d.range(next..).next() {
+        debug_assert_eq!(item_sum_sparse(d), sd);
+        next = pi + 1;
+        // Check if total volume fits
+        if sd > 0 {
+            return FitResult::failed(!guessed);
+        }
+        // pi points to a negative number -> forget it and remove from the sum
+        if v < 0 {
+            sd -= (pi as i64) * v;
+            d.remove(&pi);
+        }
+        // pi points to items in need of packing -> resolve with best fit
+        while let Some(&pv) = d.get(&pi).filter(|v| **v > 0) {
+            // Find next negative count, fail if all counts are non-neg
+            ni = match d.range(ni..).find(|(_, v)| **v < 0) {
+                Some((&ni, _)) => ni,
+                None => {
+                    assert!(sd > 0);
+                    return FitResult::failed(!guessed);
+                }
+            };
+            debug_assert!(ni > pi);
+            // How many of pi pieces fit into a ni slot
+            let moved = min(pv, (ni as i64) / (pi as i64));
+            debug_assert!(moved > 0);
+            // Remaining size of the ni slot
+            let rem = (ni as i64) - moved * (pi as i64);
+            guessed = true;
+            add(d, ni, 1);
+            add(d, pi, -moved);
+            if rem > pi as i64 {
+                // Remainder is larger than pi, remember it
+                add(d, rem as usize, -1);
+                ni = min(rem as usize, ni);
+            } else {
+                // Remainder is smaller than pi, forget it and remove it from sd
+                sd += rem;
+            }
+        }
+    }
+    // All positive counts resolved, so the volume fits
+    assert!(sd == 0);
+    FitResult::Fits
+}
+
+/// Same as `fits_into_branching`, for sparse item sets.
+/// Iterates only over the non-zero counts and gives the same results.
+pub fn fits_into_branching_sparse<T: Count>(
+    a: &[(u32, T)],
+    b: &[(u32, T)],
+    branchings: usize,
+) -> FitResult {
+    if !bounds_may_fit_sparse(a, b) {
+        return FitResult::DoesNotFit;
+    }
+    let mut d = prep_diff_sparse(a, b);
+    let sd = item_sum_sparse(&d);
+    if branchings <= 1 {
+        fits_into_bestfit_sparse_internal(&mut d, sd, true)
+    } else {
+        fits_into_branching_sparse_internal(&mut d, sd, branchings)
+    }
+}
+
+/// A node of the search in `fits_into_branching_sparse_internal`, as in `fits_into_branching_internal`
+struct SparseFrame {
+    d: SparseDiff,
+    sd: i64,
+    hp: usize,
+    negs: Vec<usize>,
+    next: usize,
+    part: usize,
+    brs: usize,
+    proven: bool,
+}
+
+/// Depth-first search with an explicit stack (see `fits_into_branching_internal`); `d` may be edited in any way.
+pub fn fits_into_branching_sparse_internal(
+    d: &mut SparseDiff,
+    sd: i64,
+    branchings: usize,
+) -> FitResult {
+    let mut stack = match branching_sparse_node(d, sd, branchings) {
+        Ok(r) => return r,
+        Err(f) => vec![f],
+    };
+    // Result of the last explored branch of the top frame
+    let mut child: Option<FitResult> = None;
+    while let Some(f) = stack.last_mut() {
+        if let Some(r) = child.take() {
+            match r {
+                FitResult::Fits => return FitResult::Fits,
+                FitResult::DoesNotFit => {}
+                FitResult::Unknown => f.proven = false,
+            }
+            f.brs -= min(f.brs, f.part);
+            f.next += 1;
+        }
+        if f.next == f.negs.len() {
+            child = Some(FitResult::failed(f.proven));
+            stack.pop();
+            continue;
+        }
+        let (hp, neg) = (f.hp, f.negs[f.next]);
+        let mut d2 = f.d.clone();
+        add(&mut d2, neg, 1);
+        add(&mut d2, hp, -1);
+        add(&mut d2, neg - hp, -1);
+        debug_assert_eq!(item_sum_sparse(&d2), f.sd);
+        let (sd, budget) = (f.sd, min(f.brs, f.part));
+        match branching_sparse_node(&mut d2, sd, budget) {
+            Ok(r) => child = Some(r),
+            Err(f) => stack.push(f),
+        }
+    }
+    child.unwrap()
+}
+
+/// Decide the node `d` with the given budget right away, or return its branches
+fn branching_sparse_node(
+    d: &mut SparseDiff,
+    sd: i64,
+    branchings: usize,
+) -> Result<FitResult, SparseFrame> {
+    if branchings <= 1 {
+        // trim_upper_bins is already done if at least one branching happened
+        return Ok(fits_into_bestfit_sparse_internal(d, sd, false));
+    }
+
+    let mut sd = sd;
+    // filter lower negatives (under the lowest positive) and check sd <= 0
+    while let Some((&ln, &v)) = d.iter().next().filter(|(_, v)| **v < 0) {
+        if !d.values().any(|v| *v > 0) {
+            break;
+        }
+        sd -= v * ln as i64;
+        d.remove(&ln);
+        debug_assert_eq!(item_sum_sparse(d), sd);
+        if sd > 0 {
+            return Ok(FitResult::DoesNotFit);
+        }
+    }
+
+    // find upper non-zero bins
+    let hpos = d.iter().rev().find(|(_, v)| **v > 0).map(|(s, _)| *s);
+    let hneg = d.iter().rev().find(|(_, v)| **v < 0).map(|(s, _)| *s);
+    match (hpos, hneg) {
+        // Trivially fits, all bins non-positive
+        (None, _) => Ok(FitResult::Fits),
+        // Only non-zero bin is positive, fail
+        (Some(_), None) => Ok(FitResult::DoesNotFit),
+        // Largest non-zero bin positive, fail
+        (Some(hp), Some(hn)) if hp > hn => Ok(FitResult::DoesNotFit),
+        // Largest non-zero bin negative, carry on
+        (Some(hp), Some(_)) => {
+            // collect all negative values above hp
+            let mut negs: Vec<usize> = d
+                .range(hp..)
+                .filter(|(_, v)| **v < 0)
+                .map(|(s, _)| *s)
+                .collect();
+            // Refuted only if all the branches are explored and refuted
+            let proven = branchings >= negs.len();
+            // limit to how many branches we have, lower negs first
+            negs.truncate(branchings);
+            Err(SparseFrame {
+                d: d.clone(),
+                sd,
+                hp,
+                part: branchings.div_ceil(negs.len()),
+                negs,
+                next: 0,
+                brs: branchings,
+                proven,
+            })
+        }
+    }
+}
+
+/// Convert a list of item sizes into a sparse item set, checking that all sizes
+/// are at most `max_size` and that the counts fit into `T` (same as `try_sizes_to_counts`)
+pub fn try_sizes_to_sparse<T: Count>(
+    sizes: &[usize],
+    max_size: usize,
+) -> Result<SparseCounts<T>, RangeError> {
+    check_sizes_range(sizes, max_size)?;
+    let mut ss = sizes.to_vec();
+    ss.sort_unstable();
+    let mut res = Vec::new();
+    for chunk in ss.chunk_by(|x, y| x == y) {
+        let (size, count) = (chunk[0], chunk.len());
+        if count > T::MAX_USIZE {
+            return Err(RangeError::CountOverflow {
+                size,
+                count,
+                max_count: T::MAX_USIZE,
+            });
+        }
+        res.push((size as u32, T::from_usize(count)));
+    }
+    Ok(res)
+}
+
+/// Convert `(size, count)` pairs (in any order, sizes may repeat) into a sparse item set,
+/// checking that there are no items of size 0, all sizes are at most `max_size` (and `u32::MAX`)
+/// and that the counts fit into `T`
+pub fn try_pairs_to_sparse<T: Count>(
+    pairs: &[(usize, usize)],
+    max_size: usize,
+) -> Result<SparseCounts<T>, RangeError> {
+    let max_size = max_size.min(u32::MAX as usize);
+    let mut ps: Vec<(usize, usize)> = Vec::with_capacity(pairs.len());
+    for (index, &(size, count)) in pairs.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        if size == 0 {
+            return Err(RangeError::ZeroSizeCount { count });
+        }
+        if size > max_size {
+            return Err(RangeError::SizeTooLarge {
+                index,
+                size,
+                max_size,
+            });
+        }
+        ps.push((size, count));
+    }
+    ps.sort_unstable();
+    let mut res: SparseCounts<T> = Vec::with_capacity(ps.len());
+    for chunk in ps.chunk_by(|x, y| x.0 == y.0) {
+        let size = chunk[0].0;
+        let count = chunk
+            .iter()
+            .try_fold(0usize, |acc, (_, c)| acc.checked_add(*c))
+            .unwrap_or(usize::MAX);
+        if count > T::MAX_USIZE {
+            return Err(RangeError::CountOverflow {
+                size,
+                count,
+                max_count: T::MAX_USIZE,
+            });
+        }
+        res.push((size as u32, T::from_usize(count)));
+    }
+    Ok(res)
+}
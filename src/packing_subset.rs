@@ -0,0 +1,184 @@
+use crate::packing_branching::fits_into_branching;
+use crate::packing_common::{item_sum, Count, FitResult};
+use crate::packing_exact::fits_into_exact;
+use std::cmp::min;
+use std::collections::BTreeMap;
+
+/// The largest packable part of an item set, see `max_packable`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaxPackable<T> {
+    /// Total size of the chosen items
+    pub volume: u64,
+    /// The chosen items as a count vector, as long as the items' one
+    pub items: Vec<T>,
+    /// Whether `volume` is proven maximal (always with `exact`)
+    pub optimal: bool,
+}
+
+/// Choose a subset of the "items" `a` with the maximum total size that fits into the "bins" `b`.
+///
+/// If `a` fits as a whole (by `fits_into_branching`), that is the answer. Otherwise the heuristic
+/// packs the items, largest first, each into the bin with the least remaining space that is enough
+/// (skipping the items that fit nowhere), and then adds as many of the skipped items as
+/// `fits_into_branching` still finds a packing for, largest first.
+///
+/// With `exact`, a branch-and-bound search chooses the counts of the items of every size, largest first,
+/// pruning when the chosen and the remaining items can't beat the best subset found. The subsets
+/// are checked with `fits_into_branching`, falling back to `fits_into_exact` when that gives up;
+/// any subset of a packable set is packable, so the largest packable count of every size is found by bisection.
+/// This is exponential in the worst case.
+///
+/// The result is optimal whenever all the items or all the bins are used.
+pub fn max_packable<T: Count>(a: &[T], b: &[T], branching: usize, exact: bool) -> MaxPackable<T> {
+    let a_volume = item_sum(a) as u64;
+    let upper = min(a_volume, item_sum(b) as u64);
+    let mut s = Search {
+        a,
+        b,
+        chosen: vec![T::default(); a.len()],
+        branching,
+        exact,
+        best: MaxPackable {
+            volume: 0,
+            items: vec![T::default(); a.len()],
+            optimal: false,
+        },
+        upper,
+    };
+    if s.fits(a) {
+        s.best.items = a.to_vec();
+        s.best.volume = a_volume;
+    } else {
+        s.initial();
+        if exact {
+            // Volume of the items of sizes below `size`
+            let mut below = vec![0u64; a.len() + 1];
+            for size in 1..a.len() {
+                below[size + 1] = below[size] + size as u64 * a[size].as_usize() as u64;
+            }
+            s.chosen.iter_mut().for_each(|c| *c = T::default());
+            s.search(a.len().saturating_sub(1), 0, &below);
+        }
+    }
+    s.best.optimal = exact || s.best.volume == upper;
+    s.best
+}
+
+/// Branch-and-bound state: the current subset `chosen` and the best one found
+struct Search<'a, T> {
+    a: &'a [T],
+    b: &'a [T],
+    chosen: Vec<T>,
+    branching: usize,
+    exact: bool,
+    best: MaxPackable<T>,
+    /// The volume of the items or of the bins, whichever is smaller
+    upper: u64,
+}
+
+impl<T: Count> Search<'_, T> {
+    /// Check if the items fit into the bins (see `max_packable`)
+    fn fits(&self, items: &[T]) -> bool {
+        match fits_into_branching(items, self.b, self.branching) {
+            FitResult::Fits => true,
+            FitResult::Unknown if self.exact => fits_into_exact(items, self.b).fits(),
+            _ => false,
+        }
+    }
+
+    fn record(&mut self, volume: u64) {
+        if volume > self.best.volume {
+            self.best.volume = volume;
+            self.best.items.copy_from_slice(&self.chosen);
+        }
+    }
+
+    /// The largest count of items of `size` up to `max` that can be added to `chosen`
+    fn max_addable(&mut self, size: usize, max: usize) -> usize {
+        let base = self.chosen[size].as_usize();
+        let (mut lo, mut hi) = (0, max);
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            self.chosen[size] = T::from_usize(base + mid);
+            if self.fits(&self.chosen) {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        self.chosen[size] = T::from_usize(base);
+        lo
+    }
+
+    /// Best-fit decreasing into the bins, skipping the items that do not fit, then add
+    /// the skipped items while they fit
+    fn initial(&mut self) {
+        // Numbers of bins by their remaining space
+        let mut free: BTreeMap<usize, usize> = BTreeMap::new();
+        for (size, c) in self.b.iter().enumerate().skip(1) {
+            if c.as_usize() > 0 {
+                free.insert(size, c.as_usize());
+            }
+        }
+        let mut volume = 0;
+        for size in (1..self.a.len()).rev() {
+            let mut left = self.a[size].as_usize();
+            // The items fill the bins of the least remaining space one by one
+            while left > 0 {
+                let Some((&rem, &n)) = free.range(size..).next() else {
+                    break;
+                };
+                let per_bin = rem / size;
+                let packed = min(left, per_bin.saturating_mul(n));
+                let (full, part) = (packed / per_bin, packed % per_bin);
+                let moved = full + (part > 0) as usize;
+                if moved == n {
+                    free.remove(&rem);
+                } else {
+                    *free.get_mut(&rem).unwrap() -= moved;
+                }
+                if full > 0 && rem > per_bin * size {
+                    *free.entry(rem - per_bin * size).or_default() += full;
+                }
+                if part > 0 {
+                    *free.entry(rem - part * size).or_default() += 1;
+                }
+                left -= packed;
+            }
+            self.chosen[size] = T::from_usize(self.a[size].as_usize() - left);
+            volume += (size * (self.a[size].as_usize() - left)) as u64;
+        }
+        for size in (1..self.a.len()).rev() {
+            let skipped = (self.a[size].as_usize() - self.chosen[size].as_usize())
+                .min((self.upper.saturating_sub(volume) / size as u64) as usize);
+            if skipped > 0 {
+                let add = self.max_addable(size, skipped);
+                self.chosen[size] = T::from_usize(self.chosen[size].as_usize() + add);
+                volume += (size * add) as u64;
+            }
+        }
+        self.record(volume);
+    }
+
+    /// Choose the counts of the items of `size` and below, with the larger ones fixed in `chosen`
+    /// (which fits). `below[s]` is the volume of all the items of sizes below `s`.
+    fn search(&mut self, size: usize, volume: u64, below: &[u64]) {
+        if self.best.volume == self.upper || volume + below[size + 1] <= self.best.volume {
+            return;
+        }
+        if size == 0 {
+            self.record(volume);
+            return;
+        }
+        let max = self.max_addable(size, self.a[size].as_usize());
+        for c in (0..=max).rev() {
+            let v = volume + (size * c) as u64;
+            if v + below[size] <= self.best.volume {
+                break;
+            }
+            self.chosen[size] = T::from_usize(c);
+            self.search(size - 1, v, below);
+        }
+        self.chosen[size] = T::default();
+    }
+}
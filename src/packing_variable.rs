@@ -0,0 +1,341 @@
+use crate::packing_branching::fits_into_branching_log;
+use crate::packing_common::{check_counts, item_sum, Count, FitResult, RangeError};
+use crate::packing_witness::{packing_into_exact, replay_moves, Packing};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BTreeMap;
+
+/// A type of bins for `cheapest_bins`: bins of `size` costing `cost` each,
+/// at most `available` of them (`None` for an unlimited supply)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinType {
+    pub size: usize,
+    pub cost: u64,
+    pub available: Option<usize>,
+}
+
+/// A set of bins holding the items, see `cheapest_bins`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheapestBins {
+    /// Number of bins of every bin type, in the order given
+    pub counts: Vec<usize>,
+    /// Total cost of the bins
+    pub cost: u64,
+    /// Whether the cost is proven minimal (always with `exact`)
+    pub optimal: bool,
+    /// The packing into the chosen bins (bin types of the same size are not told apart)
+    pub packing: Packing,
+}
+
+/// Choose a cheapest set of bins of the given `types` holding the items `a` (variable-sized bin packing).
+/// Returns `None` if the items do not fit into any set of the available bins
+/// (with `exact=false`, if none was found).
+///
+/// The heuristic first opens bins for the items, largest first, as in best-fit decreasing:
+/// an item goes into the open bin with the least space left, or into a new bin of the type with
+/// the lowest cost per size among those large enough. This set of bins is then improved by
+/// removing single bins or replacing them with cheaper ones while the items still fit,
+/// with `fits_into_branching` as the feasibility check.
+///
+/// With `exact`, a branch-and-bound search over the numbers of bins of every type (largest first)
+/// proves the optimum, starting from the heuristic cost. The partial sets of bins are pruned
+/// by the cost of covering the remaining item volume at the lowest cost per size, and the complete
+/// ones are checked with `fits_into_branching`, falling back to `fits_into_exact` when that gives up.
+/// This is exponential in the worst case.
+///
+/// The count vectors of the bins are as long as the largest bin type, so the item and bin sizes must
+/// be at most `max_size` (a bin type of a larger size fails with `SizeTooLarge` at its index).
+/// The costs saturate at `u64::MAX`.
+pub fn cheapest_bins<T: Count>(
+    a: &[T],
+    types: &[BinType],
+    max_size: usize,
+    branching: usize,
+    exact: bool,
+) -> Result<Option<CheapestBins>, RangeError> {
+    check_counts(a, max_size)?;
+    if let Some((index, t)) = types.iter().enumerate().find(|(_, t)| t.size > max_size) {
+        return Err(RangeError::SizeTooLarge {
+            index,
+            size: t.size,
+            max_size,
+        });
+    }
+    let a: Vec<u32> = a.iter().map(|c| c.as_usize() as u32).collect();
+    // The largest item must fit into some available type
+    let largest = a.iter().rposition(|c| *c > 0).unwrap_or(0);
+    if largest > 0
+        && !types
+            .iter()
+            .any(|t| t.size >= largest && t.available != Some(0))
+    {
+        return Ok(None);
+    }
+    // In a minimal set every bin holds some item, so there are at most as many bins of a type
+    // as items fitting into it
+    let mut fitting = 0;
+    let mut fitting_upto = vec![0; a.len()];
+    for (s, c) in a.iter().enumerate() {
+        fitting += *c as usize;
+        fitting_upto[s] = fitting;
+    }
+    let max_counts: Vec<usize> = types
+        .iter()
+        .map(|t| {
+            let fit = fitting_upto.get(t.size).map_or(fitting, |f| *f);
+            fit.min(t.available.unwrap_or(usize::MAX))
+                .min(u32::MAX as usize)
+        })
+        .collect();
+    let len = types.iter().map(|t| t.size + 1).max().unwrap_or(0);
+    let mut s = Search {
+        a: &a,
+        types,
+        max_counts,
+        b: vec![0; len.max(a.len())],
+        counts: vec![0; types.len()],
+        branching,
+        exact,
+        best: None,
+        order: Vec::new(),
+        cheapest_after: Vec::new(),
+    };
+
+    s.initial();
+    if s.best.is_none() {
+        // Not even all the available bins hold the items (proven with `exact`)
+        return Ok(None);
+    }
+    s.improve();
+    if exact {
+        let volume = item_sum(&a) as u64;
+        s.order = (0..types.len()).collect();
+        s.order.sort_by(|&i, &j| {
+            types[j]
+                .size
+                .cmp(&types[i].size)
+                .then(types[i].cost.cmp(&types[j].cost))
+        });
+        s.cheapest_after = (0..=types.len())
+            .map(|k| s.cheapest_per_size(&s.order[k..]))
+            .collect();
+        s.counts.iter_mut().for_each(|c| *c = 0);
+        s.b.iter_mut().for_each(|c| *c = 0);
+        s.search(0, 0, 0, volume);
+        if let Some(best) = &mut s.best {
+            best.optimal = true;
+        }
+    }
+    Ok(s.best)
+}
+
+/// Branch-and-bound state: the bins `b` of the current set given by `counts` of every type
+struct Search<'a> {
+    a: &'a [u32],
+    types: &'a [BinType],
+    max_counts: Vec<usize>,
+    b: Vec<u32>,
+    counts: Vec<usize>,
+    branching: usize,
+    exact: bool,
+    best: Option<CheapestBins>,
+    /// Exact search: the types by decreasing size, and the one with the lowest cost per size
+    /// among `order[k..]` for every `k`
+    order: Vec<usize>,
+    cheapest_after: Vec<Option<usize>>,
+}
+
+impl Search<'_> {
+    /// Pack the items into the bins `b` (see `cheapest_bins`)
+    fn find_packing(&self) -> Option<Packing> {
+        let mut moves = Vec::new();
+        match fits_into_branching_log(self.a, &self.b, self.branching, &mut moves) {
+            FitResult::Fits => Some(replay_moves(self.a, &self.b, &moves)),
+            FitResult::Unknown if self.exact => packing_into_exact(self.a, &self.b),
+            _ => None,
+        }
+    }
+
+    /// Total cost of the current set, saturating at `u64::MAX`
+    fn cost(&self) -> u64 {
+        self.counts
+            .iter()
+            .zip(self.types)
+            .fold(0u64, |sum, (c, t)| {
+                sum.saturating_add((*c as u64).saturating_mul(t.cost))
+            })
+    }
+
+    fn set_count(&mut self, t: usize, count: usize) {
+        let size = self.types[t].size;
+        self.b[size] = self.b[size] - self.counts[t] as u32 + count as u32;
+        self.counts[t] = count;
+    }
+
+    /// Record the current set of bins if it holds the items and is cheaper than the best one
+    fn try_current(&mut self) -> bool {
+        let cost = self.cost();
+        if self.best.as_ref().is_some_and(|b| b.cost <= cost) {
+            return false;
+        }
+        match self.find_packing() {
+            Some(packing) => {
+                self.best = Some(CheapestBins {
+                    counts: self.counts.clone(),
+                    cost,
+                    optimal: false,
+                    packing,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Compare the cost per size of two bin types
+    fn cmp_per_size(&self, i: usize, j: usize) -> Ordering {
+        let (ti, tj) = (&self.types[i], &self.types[j]);
+        (ti.cost as u128 * tj.size as u128).cmp(&(tj.cost as u128 * ti.size as u128))
+    }
+
+    fn cheapest_per_size(&self, types: &[usize]) -> Option<usize> {
+        types
+            .iter()
+            .copied()
+            .filter(|&t| self.types[t].size > 0 && self.max_counts[t] > 0)
+            .min_by(|&i, &j| {
+                self.cmp_per_size(i, j)
+                    .then(self.types[i].cost.cmp(&self.types[j].cost))
+            })
+    }
+
+    /// Open bins for the items as in best-fit decreasing, or try all the available bins if that fails
+    fn initial(&mut self) {
+        let mut packing: Packing = Vec::new();
+        // Indices of the open bins by their remaining space
+        let mut free: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (size, c) in self.a.iter().enumerate().rev() {
+            for _ in 0..*c {
+                let (bin, rem) = match free.range(size..).next().map(|(rem, _)| *rem) {
+                    Some(rem) => {
+                        let slots = free.get_mut(&rem).unwrap();
+                        let bin = slots.pop().unwrap();
+                        if slots.is_empty() {
+                            free.remove(&rem);
+                        }
+                        (bin, rem)
+                    }
+                    None => {
+                        let fitting: Vec<usize> = (0..self.types.len())
+                            .filter(|&t| {
+                                self.types[t].size >= size && self.counts[t] < self.max_counts[t]
+                            })
+                            .collect();
+                        let Some(t) = self.cheapest_per_size(&fitting) else {
+                            for t in 0..self.types.len() {
+                                self.set_count(t, self.max_counts[t]);
+                            }
+                            self.try_current();
+                            return;
+                        };
+                        self.set_count(t, self.counts[t] + 1);
+                        packing.push((self.types[t].size, Vec::new()));
+                        (packing.len() - 1, self.types[t].size)
+                    }
+                };
+                packing[bin].1.push(size);
+                if rem > size {
+                    free.entry(rem - size).or_default().push(bin);
+                }
+            }
+        }
+        packing.sort_by_key(|bin| Reverse(bin.0));
+        self.best = Some(CheapestBins {
+            counts: self.counts.clone(),
+            cost: self.cost(),
+            optimal: false,
+            packing,
+        });
+    }
+
+    /// Remove single bins or replace them with cheaper ones while the items fit, largest savings first
+    fn improve(&mut self) {
+        if self.best.is_none() {
+            return;
+        }
+        loop {
+            let mut moves = Vec::new();
+            for i in (0..self.types.len()).filter(|&i| self.counts[i] > 0) {
+                moves.push((self.types[i].cost, i, None));
+                for j in 0..self.types.len() {
+                    if self.types[j].cost < self.types[i].cost
+                        && self.counts[j] < self.max_counts[j]
+                    {
+                        moves.push((self.types[i].cost - self.types[j].cost, i, Some(j)));
+                    }
+                }
+            }
+            moves.retain(|m| m.0 > 0);
+            moves.sort_by_key(|m| Reverse(m.0));
+            let mut improved = false;
+            for (_, i, j) in moves {
+                self.set_count(i, self.counts[i] - 1);
+                if let Some(j) = j {
+                    self.set_count(j, self.counts[j] + 1);
+                }
+                if self.try_current() {
+                    improved = true;
+                    break;
+                }
+                self.set_count(i, self.counts[i] + 1);
+                if let Some(j) = j {
+                    self.set_count(j, self.counts[j] - 1);
+                }
+            }
+            if !improved {
+                break;
+            }
+        }
+    }
+
+    /// Lower bound on the cost of bins of the types `order[k..]` with total size at least `volume`
+    fn cost_bound(&self, k: usize, volume: u64) -> u64 {
+        if volume == 0 {
+            return 0;
+        }
+        match self.cheapest_after[k] {
+            Some(t) => {
+                let t = &self.types[t];
+                let c = (volume as u128 * t.cost as u128).div_ceil(t.size as u128);
+                c.try_into().unwrap_or(u64::MAX)
+            }
+            None => u64::MAX,
+        }
+    }
+
+    /// Try the numbers of bins of the type `order[k]`, with the larger types fixed
+    fn search(&mut self, k: usize, cost: u64, volume: u64, needed: u64) {
+        if k == self.order.len() {
+            if volume >= needed {
+                self.try_current();
+            }
+            return;
+        }
+        let t = self.order[k];
+        let (size, unit) = (self.types[t].size as u64, self.types[t].cost);
+        for c in 0..=self.max_counts[t] {
+            let cost = cost.saturating_add((c as u64).saturating_mul(unit));
+            let best = self.best.as_ref().map(|b| b.cost);
+            if cost == u64::MAX || best.is_some_and(|b| cost >= b) {
+                break;
+            }
+            let volume = volume + c as u64 * size;
+            let bound = self.cost_bound(k + 1, needed.saturating_sub(volume));
+            if bound == u64::MAX || best.is_some_and(|b| cost.saturating_add(bound) >= b) {
+                continue;
+            }
+            self.set_count(t, c);
+            self.search(k + 1, cost, volume, needed);
+        }
+        self.set_count(t, 0);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::packing_branching::fits_into_branching_log;
+#[cfg(any(feature = "python", test))]
+use crate::packing_common::{bounds_may_fit, item_sum, FitResult};
+use crate::packing_common::{Count, Move};
+use crate::packing_exact::fits_into_exact_log;
+
+/// A packing of items into bins: every bin of the bin set as `(bin size, item sizes)`,
+/// bins and items inside them ordered by decreasing size.
+pub type Packing = Vec<(usize, Vec<usize>)>;
+
+/// Find a packing of "items" `a` into "bins" `b` with `fits_into_branching`.
+/// Returns `None` if no packing was found.
+pub fn packing_into_branching<T: Count>(a: &[T], b: &[T], branchings: usize) -> Option<Packing> {
+    let mut moves = Vec::new();
+    if fits_into_branching_log(a, b, branchings, &mut moves).fits() {
+        Some(replay_moves(a, b, &moves))
+    } else {
+        None
+    }
+}
+
+/// Find a packing of "items" `a` into "bins" `b` with `fits_into_exact`.
+/// Returns `None` if no packing exists.
+pub fn packing_into_exact<T: Count>(a: &[T], b: &[T]) -> Option<Packing> {
+    let mut moves = Vec::new();
+    if fits_into_exact_log(a, b, &mut moves).fits() {
+        Some(replay_moves(a, b, &moves))
+    } else {
+        None
+    }
+}
+
+/// A packing check with its diagnostics, see `fit_report`
+#[cfg(any(feature = "python", test))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FitReport {
+    pub result: FitResult,
+    /// `"bestfit"`, `"branching"` or `"exact"`
+    pub algorithm: &'static str,
+    /// Total sizes of the items and of the bins
+    pub item_volume: i64,
+    pub bin_volume: i64,
+    /// Whether the lower bounds of `bounds_may_fit` hold
+    pub bounds_ok: bool,
+    /// The packing, if one was found
+    pub packing: Option<Packing>,
+}
+
+/// Check if the "items" `a` fit into the "bins" `b` with `fits_into_exact` if `exact`, otherwise
+/// with `fits_into_branching` (best-fit for `branching <= 1`), and report how the check went.
+#[cfg(any(feature = "python", test))]
+pub(crate) fn fit_report<T: Count>(a: &[T], b: &[T], branching: usize, exact: bool) -> FitReport {
+    let mut moves = Vec::new();
+    let result = if exact {
+        fits_into_exact_log(a, b, &mut moves)
+    } else {
+        fits_into_branching_log(a, b, branching, &mut moves)
+    };
+    FitReport {
+        result,
+        algorithm: match (exact, branching) {
+            (true, _) => "exact",
+            (false, 0 | 1) => "bestfit",
+            (false, _) => "branching",
+        },
+        item_volume: item_sum(a),
+        bin_volume: item_sum(b),
+        bounds_ok: bounds_may_fit(a, b),
+        packing: result.fits().then(|| replay_moves(a, b, &moves)),
+    }
+}
+
+/// Reconstruct the packing from the moves recorded by a successful packing algorithm.
+///
+/// The difference vector `d=a-b` counts the unpacked items minus the free slots (bins or
+/// their remaining space) of every size, with items cancelling out slots of the same size.
+/// Here we keep the actual bins, and for every move put the items into any bin with
+/// the given remaining space (these are interchangeable). Once all moves are done, `d` has
+/// no positive counts, so every remaining item has a bin with exactly its remaining size.
+pub fn replay_moves<T: Count>(a: &[T], b: &[T], moves: &[Move]) -> Packing {
+    let n = a.len().max(b.len());
+    let mut items: Vec<usize> = (0..n)
+        .map(|i| a.get(i).map_or(0, |x| x.as_usize()))
+        .collect();
+    // The packed bins as (size, items), and indices of bins by their remaining space
+    let mut bins: Packing = Vec::new();
+    let mut slots: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for (size, cnt) in b.iter().enumerate().rev() {
+        for _ in 0..cnt.as_usize() {
+            slots[size].push(bins.len());
+            bins.push((size, Vec::new()));
+        }
+    }
+
+    for m in moves {
+        let bin = slots[m.slot]
+            .pop()
+            .expect("Packing moves use a missing bin slot");
+        assert!(items[m.item] >= m.count, "Packing moves use a missing item");
+        items[m.item] -= m.count;
+        bins[bin].1.extend(std::iter::repeat_n(m.item, m.count));
+        let rem = m.slot - m.item * m.count;
+        if rem > 0 {
+            slots[rem].push(bin);
+        }
+    }
+    for (size, cnt) in items.iter().enumerate() {
+        for _ in 0..*cnt {
+            let bin = slots[size]
+                .pop()
+                .expect("Packing leaves an item without a bin");
+            bins[bin].1.push(size);
+        }
+    }
+    for (_, bin_items) in bins.iter_mut() {
+        bin_items.sort_unstable_by(|x, y| y.cmp(x));
+    }
+    bins
+}
+
+/// Independently check that `packing` packs exactly the "items" `a` into the "bins" `b`:
+/// every item is used exactly once, every bin at most as many times as present in `b`
+/// and no bin overflows. Bins not listed in `packing` are considered empty.
+///
+/// Returns a description of the first problem found.
+pub fn verify_packing<T: Count>(
+    a: &[T],
+    b: &[T],
+    packing: &[(usize, Vec<usize>)],
+) -> Result<(), String> {
+    let mut items: Vec<i64> = a.iter().map(|x| (*x).into()).collect();
+    let mut bins: Vec<i64> = b.iter().map(|x| (*x).into()).collect();
+    for (i, (bin, bin_items)) in packing.iter().enumerate() {
+        let bin = *bin;
+        match bins.get_mut(bin) {
+            Some(cnt) if *cnt > 0 => *cnt -= 1,
+            _ => return Err(format!("Bin {} (size {}) is not available", i, bin)),
+        }
+        for &item in bin_items {
+            match items.get_mut(item) {
+                Some(cnt) if *cnt > 0 && item > 0 => *cnt -= 1,
+                _ => {
+                    return Err(format!(
+                        "Item of size {} in bin {} is not available",
+                        item, i
+                    ))
+                }
+            }
+        }
+        // The items are available, so their sizes are bounded by the length of `a`
+        let total: u128 = bin_items.iter().map(|x| *x as u128).sum();
+        if total > bin as u128 {
+            return Err(format!(
+                "Bin {} (size {}) overflows with items of total size {}",
+                i, bin, total
+            ));
+        }
+    }
+    if let Some(size) = items.iter().position(|x| *x > 0) {
+        return Err(format!("Item of size {} is not packed", size));
+    }
+    Ok(())
+}
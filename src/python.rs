@@ -0,0 +1,244 @@
+use crate::errors::{self, extract_usizes, overflow_error, range_error, value_error};
+use crate::item_sets::{ItemSets, ItemSets16, ItemSets32};
+use crate::packing_branching::fits_into_branching;
+use crate::packing_common::{try_counts, try_sizes_to_counts, FitResult, RangeError};
+use crate::packing_exact::fits_into_exact;
+use crate::packing_min_bins;
+use crate::packing_subset;
+use crate::packing_variable::{self, BinType};
+use crate::packing_witness::{self, fit_report, Packing};
+use crate::storage::DENSE_MAX_SIZE;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// Check that `assignment` (a list of `(bin size, [item sizes])`) packs exactly the items
+/// given by `items_counts` into the bins given by `bins_counts`: every item is used exactly once,
+/// every bin at most as many times as present and no bin overflows. Unlisted bins are left empty.
+///
+/// Returns `True` for a valid packing, otherwise raises `BinpackValueError` describing the first problem found.
+#[pyfunction]
+#[pyo3(text_signature = "(items_counts, bins_counts, assignment)")]
+fn verify_packing(
+    items_counts: &PyAny,
+    bins_counts: &PyAny,
+    assignment: Packing,
+) -> PyResult<bool> {
+    let a = try_counts::<u32>(&extract_usizes(items_counts)?, usize::MAX).map_err(range_error)?;
+    let b = try_counts::<u32>(&extract_usizes(bins_counts)?, usize::MAX).map_err(range_error)?;
+    packing_witness::verify_packing(&a, &b, &assignment).map_err(value_error)?;
+    Ok(true)
+}
+
+/// Check if the items given by the count vector `items` fit into the bins given by `bins`,
+/// with the algorithms of the `ItemSets` queries: `branching=0` means best-fit, higher values
+/// do a partial exhaustive search limiting the branch count, `exact=True` a complete search.
+///
+/// With `diagnostics=True`, returns a dict instead of a bool: `result` (`"fits"`, `"does_not_fit"`
+/// when proven, or `"unknown"` when the heuristic gave up), `algorithm` (`"bestfit"`, `"branching"` or `"exact"`),
+/// `item_volume` and `bin_volume` (total sizes), `bounds_ok` (whether the necessary lower bound conditions hold)
+/// and `packing` (as in `ItemSets.packing_into_given`, or `None`).
+#[pyfunction(branching = "0", exact = "false", diagnostics = "false")]
+#[pyo3(text_signature = "(items, bins, /, branching=0, exact=False, diagnostics=False)")]
+fn fits_into(
+    py: Python,
+    items: &PyAny,
+    bins: &PyAny,
+    branching: usize,
+    exact: bool,
+    diagnostics: bool,
+) -> PyResult<PyObject> {
+    let a = try_counts::<u32>(&extract_usizes(items)?, usize::MAX).map_err(range_error)?;
+    let b = try_counts::<u32>(&extract_usizes(bins)?, usize::MAX).map_err(range_error)?;
+    fit_pair(py, &a, &b, branching, exact, diagnostics)
+}
+
+/// Same as `fits_into`, with the items and bins given as lists of sizes (with repetitions, in any order).
+/// Note that the count vectors are as long as the largest size, which must be at most `max_size`
+/// (by default, the default `max_size` of dense item sets).
+#[pyfunction(
+    branching = "0",
+    exact = "false",
+    diagnostics = "false",
+    max_size = "None"
+)]
+#[pyo3(
+    text_signature = "(item_sizes, bin_sizes, /, branching=0, exact=False, diagnostics=False, max_size=None)"
+)]
+fn fits_into_sizes(
+    py: Python,
+    item_sizes: &PyAny,
+    bin_sizes: &PyAny,
+    branching: usize,
+    exact: bool,
+    diagnostics: bool,
+    max_size: Option<usize>,
+) -> PyResult<PyObject> {
+    let max_size = max_size.unwrap_or(DENSE_MAX_SIZE);
+    let a =
+        try_sizes_to_counts::<u32>(&extract_usizes(item_sizes)?, max_size).map_err(range_error)?;
+    let b =
+        try_sizes_to_counts::<u32>(&extract_usizes(bin_sizes)?, max_size).map_err(range_error)?;
+    fit_pair(py, &a, &b, branching, exact, diagnostics)
+}
+
+fn fit_pair(
+    py: Python,
+    a: &[u32],
+    b: &[u32],
+    branching: usize,
+    exact: bool,
+    diagnostics: bool,
+) -> PyResult<PyObject> {
+    if !diagnostics {
+        let r = py.allow_threads(|| {
+            if exact {
+                fits_into_exact(a, b)
+            } else {
+                fits_into_branching(a, b, branching)
+            }
+        });
+        return Ok(r.fits().into_py(py));
+    }
+    let r = py.allow_threads(|| fit_report(a, b, branching, exact));
+    let d = PyDict::new(py);
+    let result = match r.result {
+        FitResult::Fits => "fits",
+        FitResult::DoesNotFit => "does_not_fit",
+        FitResult::Unknown => "unknown",
+    };
+    d.set_item("result", result)?;
+    d.set_item("algorithm", r.algorithm)?;
+    d.set_item("item_volume", r.item_volume)?;
+    d.set_item("bin_volume", r.bin_volume)?;
+    d.set_item("bounds_ok", r.bounds_ok)?;
+    d.set_item("packing", r.packing)?;
+    Ok(d.into_py(py))
+}
+
+/// Choose a subset of the items given by the count vector `items` with the maximum total size that fits
+/// into the bins given by `bins`. Returns `(volume, chosen_counts, optimal)`, with `chosen_counts` as long as `items`
+/// and `optimal` telling whether `volume` is proven maximal.
+/// The heuristic uses best-fit and `branching` as in `fits_into`, `exact=True` finds the maximum.
+#[pyfunction(branching = "0", exact = "false")]
+#[pyo3(text_signature = "(items, bins, /, branching=0, exact=False)")]
+fn max_packable(
+    py: Python,
+    items: &PyAny,
+    bins: &PyAny,
+    branching: usize,
+    exact: bool,
+) -> PyResult<(u64, Vec<u32>, bool)> {
+    let a = try_counts::<u32>(&extract_usizes(items)?, usize::MAX).map_err(range_error)?;
+    let b = try_counts::<u32>(&extract_usizes(bins)?, usize::MAX).map_err(range_error)?;
+    let r = py.allow_threads(|| packing_subset::max_packable(&a, &b, branching, exact));
+    Ok((r.volume, r.items, r.optimal))
+}
+
+/// Minimum number of bins of size `capacity` holding the items given by the list of `sizes`
+/// (classic bin packing). The first-fit and best-fit decreasing heuristics are compared to the L1 and L2
+/// lower bounds and if they disagree (and `exact=True`), an exact search proves the optimum;
+/// with `exact=False` (or a `capacity` over 2**20) the best heuristic result is returned.
+/// Items larger than `capacity` are an error.
+///
+/// With `diagnostics=True`, returns a dict instead of the number: `bins`, `optimal` (whether `bins` is proven
+/// minimal), `lower_bound`, `l1`, `l2`, `ffd` and `bfd` (bins used by the heuristics) and `packing`
+/// (a list of `(capacity, [item sizes])`).
+#[pyfunction(exact = "true", diagnostics = "false")]
+#[pyo3(text_signature = "(sizes, capacity, /, exact=True, diagnostics=False)")]
+fn min_bins(
+    py: Python,
+    sizes: &PyAny,
+    capacity: usize,
+    exact: bool,
+    diagnostics: bool,
+) -> PyResult<PyObject> {
+    let a = try_sizes_to_counts::<u32>(&extract_usizes(sizes)?, capacity).map_err(range_error)?;
+    let r = py
+        .allow_threads(|| packing_min_bins::min_bins(&a, capacity, exact))
+        .map_err(range_error)?;
+    if !diagnostics {
+        return Ok(r.bins.into_py(py));
+    }
+    let d = PyDict::new(py);
+    d.set_item("bins", r.bins)?;
+    d.set_item("optimal", r.is_optimal())?;
+    d.set_item("lower_bound", r.lower_bound)?;
+    d.set_item("l1", r.l1)?;
+    d.set_item("l2", r.l2)?;
+    d.set_item("ffd", r.ffd)?;
+    d.set_item("bfd", r.bfd)?;
+    d.set_item("packing", r.packing)?;
+    Ok(d.into_py(py))
+}
+
+/// Choose a cheapest set of bins holding the items given by the list of `item_sizes` (variable-sized bin packing).
+/// `bin_types` is a list of `(size, cost)` or `(size, cost, available)` tuples, with `available=None` for an unlimited
+/// supply. The heuristic opens bins for the items as in best-fit decreasing and then removes or replaces bins while
+/// the items still fit (checked as in `fits_into` with `branching`); `exact=True` proves the optimum by a branch-and-bound
+/// search. Returns `None` if no set of bins was found (with `exact=True`, if there is none), otherwise a dict with
+/// `counts` (the number of bins of every type), `cost`, `optimal` and `packing` (a list of `(bin size, [item sizes])`).
+/// Item and bin sizes over `max_size` (by default, the default `max_size` of dense item sets) raise `BinpackOverflowError`.
+#[pyfunction(branching = "0", exact = "false", max_size = "None")]
+#[pyo3(text_signature = "(item_sizes, bin_types, /, branching=0, exact=False, max_size=None)")]
+fn cheapest_bins(
+    py: Python,
+    item_sizes: &PyAny,
+    bin_types: &PyAny,
+    branching: usize,
+    exact: bool,
+    max_size: Option<usize>,
+) -> PyResult<PyObject> {
+    let max_size = max_size.unwrap_or(DENSE_MAX_SIZE);
+    let a =
+        try_sizes_to_counts::<u32>(&extract_usizes(item_sizes)?, max_size).map_err(range_error)?;
+    let types = bin_types
+        .iter()?
+        .map(|t| {
+            let t = t?;
+            let (size, cost, available) = match t.extract::<(usize, u64)>() {
+                Ok((size, cost)) => (size, cost, None),
+                Err(_) => t.extract()?,
+            };
+            Ok(BinType {
+                size,
+                cost,
+                available,
+            })
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let r = py
+        .allow_threads(|| packing_variable::cheapest_bins(&a, &types, max_size, branching, exact))
+        .map_err(|e| match e {
+            RangeError::SizeTooLarge { index, size, .. } => overflow_error(format!(
+                "Bin type {} has size {}, over the maximum size {}",
+                index, size, max_size
+            )),
+            e => range_error(e),
+        })?;
+    let Some(r) = r else {
+        return Ok(py.None());
+    };
+    let d = PyDict::new(py);
+    d.set_item("counts", r.counts)?;
+    d.set_item("cost", r.cost)?;
+    d.set_item("optimal", r.optimal)?;
+    d.set_item("packing", r.packing)?;
+    Ok(d.into_py(py))
+}
+
+// Init
+
+#[pymodule]
+fn binpack_pyo3(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<ItemSets>()?;
+    m.add_class::<ItemSets16>()?;
+    m.add_class::<ItemSets32>()?;
+    m.add_function(wrap_pyfunction!(verify_packing, m)?)?;
+    m.add_function(wrap_pyfunction!(fits_into, m)?)?;
+    m.add_function(wrap_pyfunction!(fits_into_sizes, m)?)?;
+    m.add_function(wrap_pyfunction!(max_packable, m)?)?;
+    m.add_function(wrap_pyfunction!(min_bins, m)?)?;
+    m.add_function(wrap_pyfunction!(cheapest_bins, m)?)?;
+    errors::add_exceptions(py, m)?;
+    Ok(())
+}
@@ -0,0 +1,971 @@
+//! Storage of item set collections, see `ItemSetStore`
+
+use crate::encoding::{decode_sets, encode_sets};
+use crate::file_format::{map_file, read_sets, write_sets, MappedSets};
+use crate::flat_vecs::FlatVecs;
+use crate::packing_branching::fits_into_branching;
+use crate::packing_common::{
+    check_counts, counts_to_sizes, try_sizes_to_counts, Count, FitResult, RangeError,
+};
+use crate::packing_exact::fits_into_exact;
+use crate::packing_sparse::{
+    dense_to_sparse, fits_into_branching_sparse, sparse_to_dense, try_pairs_to_sparse,
+    try_sizes_to_sparse, SparseCounts,
+};
+use crate::packing_subset::max_packable;
+use crate::packing_witness::{packing_into_branching, packing_into_exact, Packing};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator};
+use std::cmp::min;
+use std::fs::{self, File};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Options of the packing checks in the `ItemSetStore` queries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FitOptions {
+    /// `0` means best-fit, higher values do a partial exhaustive search limiting the branch count
+    /// (switching to best-fit afterwards)
+    pub branching: usize,
+    /// Run a complete exhaustive search instead (ignoring `branching`)
+    pub exact: bool,
+    /// Check the stored item sets in parallel (with rayon)
+    pub par: bool,
+}
+
+/// Errors of the modifying `ItemSetStore` methods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    /// The given item set is invalid
+    Range(RangeError),
+    /// The item sets are memory-mapped, hence read-only
+    ReadOnly,
+}
+
+impl From<RangeError> for StoreError {
+    fn from(e: RangeError) -> Self {
+        StoreError::Range(e)
+    }
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::Range(e) => e.fmt(f),
+            StoreError::ReadOnly => f.write_str(READ_ONLY),
+        }
+    }
+}
+
+/// A collection of item sets with counts of type `T`, as in the Python `ItemSets` classes.
+///
+/// Item sets are given as count vectors (`counts[size]` items of every size, `counts[0] == 0`),
+/// `(size, count)` pairs (as `SetRef::Sparse`) or lists of item sizes and stored in one buffer,
+/// either as count vectors or sparse (as `(size, count)` pairs of the present sizes), or read-only
+/// in a memory-mapped file (see `open`). Given item sets are validated against `max_size` and the count type.
+///
+/// The exact search and `max_packable` run on count vectors, as do the checks between a sparse
+/// and a dense item set: these fail with `RangeError::SizeTooLarge` for sparse item sets with sizes
+/// over `DENSE_MAX_SIZE` instead of allocating their count vectors.
+#[derive(Debug, Clone)]
+pub struct ItemSetStore<T> {
+    pub(crate) sets: Storage<T>,
+    pub(crate) max_size: usize,
+}
+
+/// Generates the `ItemSetStore` queries with the given item set on one side of the packing
+macro_rules! store_queries {
+    ($($(#[$attr:meta])* $name:ident, $method:ident, $res:ty, $given_into:expr;)*) => {$(
+        $(#[$attr])*
+        pub fn $name<'a>(
+            &self,
+            given: impl Into<SetRef<'a, T>>,
+            opts: FitOptions,
+        ) -> Result<$res, RangeError> {
+            let gc = self.given(given.into())?;
+            let gc = gc.as_ref();
+            self.check_dense_fallback(gc, opts.exact)?;
+            Ok(self.sets.$method(opts.par, |sc| {
+                let (a, b) = if $given_into { (gc, sc) } else { (sc, gc) };
+                fits_into(a, b, opts.branching, opts.exact).fits()
+            }))
+        }
+    )*};
+}
+
+/// Generates the batch queries, running a query of `store_queries!` for every item set of `given`
+macro_rules! store_batch_queries {
+    ($($(#[$attr:meta])* $name:ident, $method:ident, $res:ty, $given_into:expr;)*) => {$(
+        $(#[$attr])*
+        pub fn $name(
+            &self,
+            given: &ItemSetStore<T>,
+            opts: FitOptions,
+        ) -> Result<Vec<$res>, RangeError> {
+            self.check_dense_fallback_with(&given.sets, opts.exact)?;
+            // Nested parallel iterators, so that rayon balances the whole grid
+            Ok(given.sets.map(opts.par, |gc| {
+                self.sets.$method(opts.par, |sc| {
+                    let (a, b) = if $given_into { (gc, sc) } else { (sc, gc) };
+                    fits_into(a, b, opts.branching, opts.exact).fits()
+                })
+            }))
+        }
+    )*};
+}
+
+impl<T: Count> ItemSetStore<T> {
+    /// An empty collection of item sets with sizes up to `max_size`.
+    ///
+    /// Panics if `sparse` and `max_size > u32::MAX` (sparse sizes are stored as `u32`).
+    pub fn new(max_size: usize, sparse: bool) -> Self {
+        assert!(
+            !sparse || max_size <= u32::MAX as usize,
+            "Sparse item sets support sizes up to {}",
+            u32::MAX
+        );
+        ItemSetStore {
+            sets: Storage::new(sparse),
+            max_size,
+        }
+    }
+
+    /// Read item sets saved with `save` (see `file_format.rs`), validating the whole file.
+    /// Invalid files fail with `io::ErrorKind::InvalidData`.
+    pub fn load(path: &Path, sparse: bool) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let (sets, max_size) = read_sets(&data, sparse).map_err(invalid_data)?;
+        Ok(ItemSetStore { sets, max_size })
+    }
+
+    /// Open item sets saved with `save` memory-mapped and read-only; with `verify`,
+    /// the checksums and all the count vectors are validated first, otherwise only the layout.
+    /// Invalid files fail with `io::ErrorKind::InvalidData`. The file must not be modified while open.
+    pub fn open(path: &Path, verify: bool) -> io::Result<Self> {
+        let (sets, max_size) = MappedSets::new(map_file(path)?, verify).map_err(invalid_data)?;
+        Ok(ItemSetStore {
+            sets: Storage::Mapped(sets),
+            max_size,
+        })
+    }
+
+    /// Save the item sets (as count vectors) in the binary format of `file_format.rs`.
+    /// The file is written next to `path` and renamed over it, so an existing file is replaced
+    /// atomically and stores opened from it keep their (old) mapping.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        static TMP_ID: AtomicUsize = AtomicUsize::new(0);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            TMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp = PathBuf::from(tmp);
+        let write = || -> io::Result<()> {
+            let mut w = io::BufWriter::new(File::create(&tmp)?);
+            write_sets(&mut w, &self.sets, self.max_size)?;
+            w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&tmp, path)
+        };
+        write().inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+
+    /// Compact binary encoding of the item sets (as used for pickling)
+    pub fn encode(&self) -> Vec<u8> {
+        encode_sets(&self.sets)
+    }
+
+    /// Decode item sets from `encode`, validating them against the count type and `max_size`
+    pub fn decode(data: &[u8], sparse: bool, max_size: usize) -> Result<Self, String> {
+        Ok(ItemSetStore {
+            sets: decode_sets(data, sparse, max_size)?,
+            max_size,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.len() == 0
+    }
+
+    /// The maximum allowed item size
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.sets.is_sparse()
+    }
+
+    /// Whether the item sets are memory-mapped (and read-only)
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.sets, Storage::Mapped(_))
+    }
+
+    /// Estimate of the memory used, see the `FlatVecs` storage
+    pub fn memory_used(&self) -> usize {
+        self.sets.memory_used()
+    }
+
+    /// The `idx`-th item set
+    pub fn get(&self, idx: usize) -> Option<SetRef<'_, T>> {
+        self.sets.get(idx)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = SetRef<'_, T>> {
+        self.sets.iter()
+    }
+
+    /// The storage for modification, fails for the read-only mapped item sets
+    fn sets_mut(&mut self) -> Result<&mut Storage<T>, StoreError> {
+        match self.sets {
+            Storage::Mapped(_) => Err(StoreError::ReadOnly),
+            _ => Ok(&mut self.sets),
+        }
+    }
+
+    /// Insert an item set given by counts (or `(size, count)` pairs, as in the queries).
+    /// Fails with `StoreError::ReadOnly` if the item sets are mapped, as do the other modifying methods.
+    pub fn push_counts<'a>(&mut self, given: impl Into<SetRef<'a, T>>) -> Result<(), StoreError> {
+        let cs = self.given(given.into())?;
+        self.sets_mut()?.push(cs);
+        Ok(())
+    }
+
+    /// Same as `push_counts`, with the counts read from an iterator, e.g. straight from a buffer
+    /// (the iterator is cloned to validate the counts first)
+    pub fn push_counts_iter<I>(&mut self, counts: I) -> Result<(), StoreError>
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Clone,
+    {
+        let counts = counts.into_iter();
+        check_counts_iter(counts.clone(), self.max_size)?;
+        match self.sets_mut()? {
+            Storage::Dense(sets) => sets.push_iter(counts),
+            Storage::Sparse(sets) => sets.push_iter(
+                counts
+                    .enumerate()
+                    .filter(|(_, c)| *c != T::default())
+                    .map(|(size, c)| (size as u32, c)),
+            ),
+            Storage::Mapped(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Insert an item set given by a list of item sizes (with repetitions, in any order)
+    pub fn push_sizes(&mut self, sizes: &[usize]) -> Result<(), StoreError> {
+        let max_size = self.max_size;
+        match self.sets_mut()? {
+            Storage::Dense(sets) => sets.push(&try_sizes_to_counts(sizes, max_size)?),
+            Storage::Sparse(sets) => sets.push(&try_sizes_to_sparse(sizes, max_size)?),
+            Storage::Mapped(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Remove and return the `idx`-th item set as counts. Panics if `idx` is out of bounds.
+    pub fn remove(&mut self, idx: usize) -> Result<Vec<T>, StoreError> {
+        Ok(self.sets_mut()?.remove(idx).to_dense())
+    }
+
+    /// Keep the item sets with `keep[i]` set (of length `len()`), compacting the storage in place
+    pub fn retain(&mut self, keep: &[bool]) -> Result<(), StoreError> {
+        self.sets_mut()?.retain(keep);
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<(), StoreError> {
+        self.sets_mut()?.clear();
+        Ok(())
+    }
+
+    /// Insert the given item set unless it is dominated by a stored item set, evicting the stored
+    /// item sets it dominates. Returns whether it was inserted. With `keep_max`, a set is dominated
+    /// when it fits into another one, otherwise when another one fits into it.
+    pub fn push_if_not_dominated<'a>(
+        &mut self,
+        given: impl Into<SetRef<'a, T>>,
+        keep_max: bool,
+        opts: FitOptions,
+    ) -> Result<bool, StoreError> {
+        let gc = self.given(given.into())?;
+        self.check_dense_fallback(gc.as_ref(), opts.exact)?;
+        Ok(self
+            .sets_mut()?
+            .push_if_not_dominated(gc, opts.par, |x, y| {
+                let (a, b) = if keep_max { (y, x) } else { (x, y) };
+                fits_into(a, b, opts.branching, opts.exact).fits()
+            }))
+    }
+
+    store_queries! {
+        /// Check if any of the stored item sets fit into the item set given by `counts`
+        any_fit_into_given, any, bool, false;
+        /// Check if the item set given by `counts` fits into any of the stored item sets
+        given_fits_into_any, any, bool, true;
+        /// Check if all of the stored item sets fit into the item set given by `counts`
+        all_fit_into_given, all, bool, false;
+        /// Check if the item set given by `counts` fits into all of the stored item sets
+        given_fits_into_all, all, bool, true;
+        /// Count how many of the stored item sets fit into the item set given by `counts`
+        how_many_fit_into_given, count, usize, false;
+        /// Count into how many of the stored item sets the item set given by `counts` fits
+        given_fits_into_how_many, count, usize, true;
+        /// The sorted indices of the stored item sets that fit into the item set given by `counts`
+        which_fit_into_given, which, Vec<usize>, false;
+        /// The sorted indices of the stored item sets into which the item set given by `counts` fits
+        given_fits_into_which, which, Vec<usize>, true;
+        /// The lowest index of a stored item set that fits into the item set given by `counts`
+        first_fit_into_given, first, Option<usize>, false;
+    }
+
+    store_batch_queries! {
+        /// For each item set of `given`, check if any of the stored item sets fit into it
+        any_fit_into_each, any, bool, false;
+        /// For each item set of `given`, check if it fits into any of the stored item sets
+        each_fits_into_any, any, bool, true;
+        /// For each item set of `given`, count how many of the stored item sets fit into it
+        how_many_fit_into_each, count, usize, false;
+        /// For each item set of `given`, count into how many of the stored item sets it fits
+        each_fits_into_how_many, count, usize, true;
+    }
+
+    /// Count how many of the stored item sets provably fit, provably do not fit, and are undecided
+    /// when packed into the item set given by `counts`, as `(fits, does_not_fit, unknown)`
+    pub fn classify_fit_into_given<'a>(
+        &self,
+        given: impl Into<SetRef<'a, T>>,
+        opts: FitOptions,
+    ) -> Result<(usize, usize, usize), RangeError> {
+        let gc = self.given(given.into())?;
+        let gc = gc.as_ref();
+        self.check_dense_fallback(gc, opts.exact)?;
+        Ok(self
+            .sets
+            .classify(opts.par, |sc| fits_into(sc, gc, opts.branching, opts.exact)))
+    }
+
+    /// Same as `classify_fit_into_given`, packing the given item set into the stored ones
+    pub fn classify_given_fits_into<'a>(
+        &self,
+        given: impl Into<SetRef<'a, T>>,
+        opts: FitOptions,
+    ) -> Result<(usize, usize, usize), RangeError> {
+        let gc = self.given(given.into())?;
+        let gc = gc.as_ref();
+        self.check_dense_fallback(gc, opts.exact)?;
+        Ok(self
+            .sets
+            .classify(opts.par, |sc| fits_into(gc, sc, opts.branching, opts.exact)))
+    }
+
+    /// For every stored item set, the maximum total size of its items that fit into the item set
+    /// given by `counts` (see `max_packable`, with `opts.branching` and `opts.exact`)
+    pub fn max_packable_volume_into_given<'a>(
+        &self,
+        given: impl Into<SetRef<'a, T>>,
+        opts: FitOptions,
+    ) -> Result<Vec<u64>, RangeError> {
+        let gc = self.given(given.into())?;
+        self.check_dense_fallback(gc.as_ref(), true)?;
+        let gc = gc.to_dense();
+        Ok(self.sets.map(opts.par, |sc| {
+            max_packable(&sc.to_dense(), &gc, opts.branching, opts.exact).volume
+        }))
+    }
+
+    /// Find a packing of the `idx`-th stored item set into the item set given by `counts`
+    /// (see `packing_into_branching` and `packing_into_exact`, `opts.par` is not used).
+    /// Panics if `idx` is out of bounds.
+    pub fn packing_into_given<'a>(
+        &self,
+        idx: usize,
+        given: impl Into<SetRef<'a, T>>,
+        opts: FitOptions,
+    ) -> Result<Option<Packing>, RangeError> {
+        let gc = self.given(given.into())?;
+        let sc = self.sets.get(idx).expect("Item set index out of bounds");
+        check_dense_len(gc.as_ref())?;
+        check_dense_len(sc)?;
+        let (sc, gc) = (sc.to_dense(), gc.to_dense());
+        Ok(if opts.exact {
+            packing_into_exact(&sc, &gc)
+        } else {
+            packing_into_branching(&sc, &gc, opts.branching)
+        })
+    }
+
+    /// The sorted pairs `(i, j)` such that stored item set `i` fits into item set `j` of `other`,
+    /// computed in tiles (in parallel with `opts.par`)
+    pub fn fit_matrix(
+        &self,
+        other: &ItemSetStore<T>,
+        opts: FitOptions,
+    ) -> Result<Vec<(usize, usize)>, RangeError> {
+        self.check_dense_fallback_with(&other.sets, opts.exact)?;
+        Ok(self.sets.fit_matrix_pairs(&other.sets, opts.par, |a, b| {
+            fits_into(a, b, opts.branching, opts.exact).fits()
+        }))
+    }
+
+    /// Same as `fit_matrix`, as a bit matrix with the rows packed into bytes, each row taking
+    /// `other.len().div_ceil(8)` bytes, most significant bit first
+    pub fn fit_matrix_packed(
+        &self,
+        other: &ItemSetStore<T>,
+        opts: FitOptions,
+    ) -> Result<Vec<u8>, RangeError> {
+        self.check_dense_fallback_with(&other.sets, opts.exact)?;
+        Ok(self.sets.fit_matrix_packed(&other.sets, opts.par, |a, b| {
+            fits_into(a, b, opts.branching, opts.exact).fits()
+        }))
+    }
+
+    /// Validate the given item set and convert it to the representation of the storage.
+    /// `(size, count)` pairs may come in any order (see `try_pairs_to_sparse`).
+    pub(crate) fn given(&self, given: SetRef<T>) -> Result<SetBuf<T>, RangeError> {
+        match given {
+            SetRef::Dense(counts) => {
+                check_counts(counts, self.max_size)?;
+                Ok(self.sets.prepare(counts.to_vec()))
+            }
+            SetRef::Sparse(pairs) => {
+                let pairs: Vec<(usize, usize)> = pairs
+                    .iter()
+                    .map(|(s, c)| (*s as usize, c.as_usize()))
+                    .collect();
+                let sparse = try_pairs_to_sparse(&pairs, self.max_size)?;
+                if self.is_sparse() {
+                    Ok(SetBuf::Sparse(sparse))
+                } else {
+                    check_dense_len(SetRef::Sparse(&sparse))?;
+                    Ok(SetBuf::Dense(sparse_to_dense(&sparse)))
+                }
+            }
+        }
+    }
+
+    /// With `exact`, `fits_into` checks sparse item sets as count vectors: fail if the given one
+    /// or a stored one is over `DENSE_MAX_SIZE` (the given one is in the representation of the storage)
+    fn check_dense_fallback(&self, given: SetRef<T>, exact: bool) -> Result<(), RangeError> {
+        if exact {
+            check_dense_len(given)?;
+            self.sets.check_dense_len()?;
+        }
+        Ok(())
+    }
+
+    /// Same as `check_dense_fallback` between the stored item sets and `other`,
+    /// which are also converted to count vectors when only one of them is sparse
+    fn check_dense_fallback_with(&self, other: &Storage<T>, exact: bool) -> Result<(), RangeError> {
+        if exact || self.sets.is_sparse() != other.is_sparse() {
+            self.sets.check_dense_len()?;
+            other.check_dense_len()?;
+        }
+        Ok(())
+    }
+}
+
+/// Largest item size of a sparse item set converted to a count vector (for the dense algorithms),
+/// also the default `max_size` of dense item sets (a count vector of 4 MiB with `u32` counts)
+pub(crate) const DENSE_MAX_SIZE: usize = 1 << 20;
+
+/// Check that `set` can be converted to a count vector, see `DENSE_MAX_SIZE`
+fn check_dense_len<T: Count>(set: SetRef<T>) -> Result<(), RangeError> {
+    match set {
+        SetRef::Sparse(&[.., (size, _)]) if size as usize > DENSE_MAX_SIZE => {
+            Err(RangeError::SizeTooLarge {
+                index: size as usize,
+                size: size as usize,
+                max_size: DENSE_MAX_SIZE,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Same as `check_counts`, for counts read from an iterator
+fn check_counts_iter<T: Count>(
+    counts: impl Iterator<Item = T>,
+    max_size: usize,
+) -> Result<(), RangeError> {
+    let mut last = 0;
+    for (size, c) in counts.enumerate().filter(|(_, c)| *c != T::default()) {
+        if size == 0 {
+            return Err(RangeError::ZeroSizeCount {
+                count: c.as_usize(),
+            });
+        }
+        last = size;
+    }
+    if last > max_size {
+        return Err(RangeError::SizeTooLarge {
+            index: last,
+            size: last,
+            max_size,
+        });
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Storage of the item sets, either as count vectors or as sparse `(size, count)` pairs,
+/// or read-only count vectors in a memory-mapped file
+#[derive(Debug, Clone)]
+pub(crate) enum Storage<T> {
+    Dense(FlatVecs<T>),
+    Sparse(FlatVecs<(u32, T)>),
+    Mapped(MappedSets<T>),
+}
+
+/// A stored (or given) item set, in the representation of the storage:
+/// a count vector, or the `(size, count)` pairs of the present sizes (increasing)
+#[derive(Debug, Clone, Copy)]
+pub enum SetRef<'a, T> {
+    Dense(&'a [T]),
+    Sparse(&'a [(u32, T)]),
+}
+
+/// An owned item set, in the representation of the storage
+pub(crate) enum SetBuf<T> {
+    Dense(Vec<T>),
+    Sparse(SparseCounts<T>),
+}
+
+impl<T: Count> Storage<T> {
+    pub(crate) fn new(sparse: bool) -> Self {
+        if sparse {
+            Storage::Sparse(FlatVecs::new())
+        } else {
+            Storage::Dense(FlatVecs::new())
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Storage::Dense(sets) => sets.len(),
+            Storage::Sparse(sets) => sets.len(),
+            Storage::Mapped(sets) => sets.len(),
+        }
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> Option<SetRef<'_, T>> {
+        match self {
+            Storage::Dense(sets) => sets.get(idx).map(SetRef::Dense),
+            Storage::Sparse(sets) => sets.get(idx).map(SetRef::Sparse),
+            Storage::Mapped(sets) => sets.get(idx).map(|s| SetRef::Dense(s)),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = SetRef<'_, T>> {
+        (0..self.len()).map(move |i| self.get(i).unwrap())
+    }
+
+    pub(crate) fn par_iter(&self) -> impl IndexedParallelIterator<Item = SetRef<'_, T>> {
+        (0..self.len())
+            .into_par_iter()
+            .map(move |i| self.get(i).unwrap())
+    }
+
+    /// Whether `f` holds for any of the item sets (in parallel with `par`)
+    pub(crate) fn any<F>(&self, par: bool, f: F) -> bool
+    where
+        F: Fn(SetRef<T>) -> bool + Sync,
+    {
+        if par {
+            self.par_iter().any(&f)
+        } else {
+            self.iter().any(f)
+        }
+    }
+
+    pub(crate) fn all<F>(&self, par: bool, f: F) -> bool
+    where
+        F: Fn(SetRef<T>) -> bool + Sync,
+    {
+        if par {
+            self.par_iter().all(&f)
+        } else {
+            self.iter().all(f)
+        }
+    }
+
+    pub(crate) fn count<F>(&self, par: bool, f: F) -> usize
+    where
+        F: Fn(SetRef<T>) -> bool + Sync,
+    {
+        if par {
+            self.par_iter().filter(|sc| f(*sc)).count()
+        } else {
+            self.iter().filter(|sc| f(*sc)).count()
+        }
+    }
+
+    /// The sorted indices of the item sets where `f` holds
+    pub(crate) fn which<F>(&self, par: bool, f: F) -> Vec<usize>
+    where
+        F: Fn(SetRef<T>) -> bool + Sync,
+    {
+        if par {
+            (0..self.len())
+                .into_par_iter()
+                .filter(|i| f(self.get(*i).unwrap()))
+                .collect()
+        } else {
+            (0..self.len())
+                .filter(|i| f(self.get(*i).unwrap()))
+                .collect()
+        }
+    }
+
+    /// The lowest index where `f` holds, the same with `par`
+    pub(crate) fn first<F>(&self, par: bool, f: F) -> Option<usize>
+    where
+        F: Fn(SetRef<T>) -> bool + Sync,
+    {
+        if par {
+            self.par_iter().position_first(&f)
+        } else {
+            self.iter().position(f)
+        }
+    }
+
+    /// Tally the results of `f` as `(fits, does_not_fit, unknown)`
+    pub(crate) fn classify<F>(&self, par: bool, f: F) -> (usize, usize, usize)
+    where
+        F: Fn(SetRef<T>) -> FitResult + Sync,
+    {
+        let tally = |sc: SetRef<T>| match f(sc) {
+            FitResult::Fits => (1, 0, 0),
+            FitResult::DoesNotFit => (0, 1, 0),
+            FitResult::Unknown => (0, 0, 1),
+        };
+        let add =
+            |x: (usize, usize, usize), y: (usize, usize, usize)| (x.0 + y.0, x.1 + y.1, x.2 + y.2);
+        if par {
+            self.par_iter().map(tally).reduce(|| (0, 0, 0), add)
+        } else {
+            self.iter().map(tally).fold((0, 0, 0), add)
+        }
+    }
+
+    /// The results of `f` for all the item sets, in order
+    pub(crate) fn map<R, F>(&self, par: bool, f: F) -> Vec<R>
+    where
+        F: Fn(SetRef<T>) -> R + Sync,
+        R: Send,
+    {
+        if par {
+            self.par_iter().map(&f).collect()
+        } else {
+            self.iter().map(f).collect()
+        }
+    }
+
+    /// Compute the relation `f(self[i], other[j])` for all the pairs, computed in tiles
+    /// (in parallel with `par`). Returns the rows bit-packed into bytes, each row taking
+    /// `other.len().div_ceil(8)` bytes, with the most significant bit first (as `numpy.packbits`).
+    pub(crate) fn fit_matrix_packed<F>(&self, other: &Storage<T>, par: bool, f: F) -> Vec<u8>
+    where
+        F: Fn(SetRef<T>, SetRef<T>) -> bool + Sync,
+    {
+        let row_bytes = other.len().div_ceil(8);
+        let tile = |(rows, cols): &(Range<usize>, Range<usize>)| {
+            let mut block = vec![0u8; rows.len() * cols.len().div_ceil(8)];
+            for (ti, i) in rows.clone().enumerate() {
+                for (tj, j) in cols.clone().enumerate() {
+                    if f(self.get(i).unwrap(), other.get(j).unwrap()) {
+                        block[ti * cols.len().div_ceil(8) + tj / 8] |= 0x80 >> (tj % 8);
+                    }
+                }
+            }
+            block
+        };
+        let tiles = tiles(self.len(), other.len());
+        let blocks: Vec<Vec<u8>> = if par {
+            tiles.par_iter().map(tile).collect()
+        } else {
+            tiles.iter().map(tile).collect()
+        };
+        let mut res = vec![0u8; self.len() * row_bytes];
+        for ((rows, cols), block) in tiles.iter().zip(blocks) {
+            let tile_bytes = cols.len().div_ceil(8);
+            for (ti, i) in rows.clone().enumerate() {
+                let start = i * row_bytes + cols.start / 8;
+                res[start..start + tile_bytes]
+                    .copy_from_slice(&block[ti * tile_bytes..(ti + 1) * tile_bytes]);
+            }
+        }
+        res
+    }
+
+    /// Same as `fit_matrix_packed`, returning the sorted list of pairs `(i, j)` in the relation
+    pub(crate) fn fit_matrix_pairs<F>(
+        &self,
+        other: &Storage<T>,
+        par: bool,
+        f: F,
+    ) -> Vec<(usize, usize)>
+    where
+        F: Fn(SetRef<T>, SetRef<T>) -> bool + Sync,
+    {
+        let tile = |(rows, cols): &(Range<usize>, Range<usize>)| {
+            let mut pairs = Vec::new();
+            for i in rows.clone() {
+                for j in cols.clone() {
+                    if f(self.get(i).unwrap(), other.get(j).unwrap()) {
+                        pairs.push((i, j));
+                    }
+                }
+            }
+            pairs
+        };
+        let tiles = tiles(self.len(), other.len());
+        let mut res: Vec<(usize, usize)> = if par {
+            tiles.par_iter().flat_map_iter(tile).collect()
+        } else {
+            tiles.iter().flat_map(tile).collect()
+        };
+        res.sort_unstable();
+        res
+    }
+
+    pub(crate) fn is_sparse(&self) -> bool {
+        matches!(self, Storage::Sparse(_))
+    }
+
+    /// Check that the (sparse) item sets can be converted to count vectors, see `DENSE_MAX_SIZE`
+    pub(crate) fn check_dense_len(&self) -> Result<(), RangeError> {
+        match self {
+            Storage::Sparse(_) => self.iter().try_for_each(check_dense_len),
+            _ => Ok(()),
+        }
+    }
+
+    /// Convert a count vector into the representation of the storage
+    pub(crate) fn prepare(&self, counts: Vec<T>) -> SetBuf<T> {
+        match self {
+            Storage::Dense(_) | Storage::Mapped(_) => SetBuf::Dense(counts),
+            Storage::Sparse(_) => SetBuf::Sparse(dense_to_sparse(&counts)),
+        }
+    }
+
+    /// Panics if `idx` is out of bounds
+    pub(crate) fn remove(&mut self, idx: usize) -> SetBuf<T> {
+        match self {
+            Storage::Dense(sets) => SetBuf::Dense(sets.remove(idx)),
+            Storage::Sparse(sets) => SetBuf::Sparse(sets.remove(idx)),
+            Storage::Mapped(_) => panic!("{}", READ_ONLY),
+        }
+    }
+
+    /// Keep the item sets with `keep[i]` set, compacting the storage in place.
+    /// Releases the spare capacity if it is over half of the storage.
+    pub(crate) fn retain(&mut self, keep: &[bool]) {
+        match self {
+            Storage::Dense(sets) => sets.retain(keep),
+            Storage::Sparse(sets) => sets.retain(keep),
+            Storage::Mapped(_) => panic!("{}", READ_ONLY),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        match self {
+            Storage::Dense(sets) => sets.shrink_to_fit(),
+            Storage::Sparse(sets) => sets.shrink_to_fit(),
+            Storage::Mapped(_) => {}
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Storage::Dense(sets) => sets.clear(),
+            Storage::Sparse(sets) => sets.clear(),
+            Storage::Mapped(_) => panic!("{}", READ_ONLY),
+        }
+    }
+
+    /// Push `set` unless `dominates(stored, set)` for some stored set, removing the stored sets
+    /// with `dominates(set, stored)`. Returns whether `set` was pushed.
+    pub(crate) fn push_if_not_dominated<F>(
+        &mut self,
+        set: SetBuf<T>,
+        par: bool,
+        dominates: F,
+    ) -> bool
+    where
+        F: Fn(SetRef<T>, SetRef<T>) -> bool + Sync,
+    {
+        let g = set.as_ref();
+        let keep: Vec<bool> = if par {
+            if self.par_iter().any(|sc| dominates(sc, g)) {
+                return false;
+            }
+            self.par_iter().map(|sc| !dominates(g, sc)).collect()
+        } else {
+            if self.iter().any(|sc| dominates(sc, g)) {
+                return false;
+            }
+            self.iter().map(|sc| !dominates(g, sc)).collect()
+        };
+        self.retain(&keep);
+        self.push(set);
+        true
+    }
+
+    /// Panics if `set` is not in the representation of the storage (or the storage is mapped)
+    pub(crate) fn push(&mut self, set: SetBuf<T>) {
+        match (self, set) {
+            (Storage::Dense(sets), SetBuf::Dense(s)) => sets.push(&s),
+            (Storage::Sparse(sets), SetBuf::Sparse(s)) => sets.push(&s),
+            (Storage::Mapped(_), _) => panic!("{}", READ_ONLY),
+            _ => panic!("Item set representation does not match the storage"),
+        }
+    }
+
+    /// The item sets are stored in one buffer (see `FlatVecs`), using the count vector (resp. the pairs)
+    /// plus one offset per item set
+    pub(crate) fn memory_used(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + match self {
+                Storage::Dense(sets) => sets.heap_bytes(),
+                Storage::Sparse(sets) => sets.heap_bytes(),
+                // The mapped file is in the OS page cache
+                Storage::Mapped(_) => 0,
+            }
+    }
+}
+
+impl<T: Count> SetRef<'_, T> {
+    /// Length of the count vector
+    pub fn dense_len(self) -> usize {
+        match self {
+            SetRef::Dense(s) => s.len(),
+            SetRef::Sparse(s) => s.last().map_or(0, |(size, _)| *size as usize + 1),
+        }
+    }
+
+    /// The non-zero counts as `(size, count)`, sizes increasing
+    pub fn nonzero(self) -> Vec<(usize, T)> {
+        match self {
+            SetRef::Dense(s) => s
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c != T::default())
+                .map(|(size, c)| (size, *c))
+                .collect(),
+            SetRef::Sparse(s) => s.iter().map(|(size, c)| (*size as usize, *c)).collect(),
+        }
+    }
+
+    /// Write the counts into `row` (of at least `dense_len()`), leaving the other elements
+    pub fn write_dense(self, row: &mut [T]) {
+        match self {
+            SetRef::Dense(s) => row[..s.len()].copy_from_slice(s),
+            SetRef::Sparse(s) => {
+                for (size, c) in s {
+                    row[*size as usize] = *c;
+                }
+            }
+        }
+    }
+
+    pub fn to_dense(self) -> Vec<T> {
+        match self {
+            SetRef::Dense(s) => s.to_vec(),
+            SetRef::Sparse(s) => sparse_to_dense(s),
+        }
+    }
+
+    /// List of item sizes, decreasing
+    pub fn to_sizes(self) -> Vec<usize> {
+        match self {
+            SetRef::Dense(s) => counts_to_sizes(s),
+            SetRef::Sparse(s) => s
+                .iter()
+                .rev()
+                .flat_map(|(size, c)| std::iter::repeat_n(*size as usize, c.as_usize()))
+                .collect(),
+        }
+    }
+}
+
+impl<'a, T> From<&'a [T]> for SetRef<'a, T> {
+    fn from(counts: &'a [T]) -> Self {
+        SetRef::Dense(counts)
+    }
+}
+
+impl<'a, T> From<&'a Vec<T>> for SetRef<'a, T> {
+    fn from(counts: &'a Vec<T>) -> Self {
+        SetRef::Dense(counts)
+    }
+}
+
+impl<'a, T, const N: usize> From<&'a [T; N]> for SetRef<'a, T> {
+    fn from(counts: &'a [T; N]) -> Self {
+        SetRef::Dense(counts)
+    }
+}
+
+impl<T: Count> SetBuf<T> {
+    pub(crate) fn as_ref(&self) -> SetRef<'_, T> {
+        match self {
+            SetBuf::Dense(s) => SetRef::Dense(s),
+            SetBuf::Sparse(s) => SetRef::Sparse(s),
+        }
+    }
+
+    pub(crate) fn to_dense(&self) -> Vec<T> {
+        self.as_ref().to_dense()
+    }
+}
+
+pub(crate) const READ_ONLY: &str = "Memory-mapped item sets are read-only";
+
+/// Tile size for `fit_matrix_*`, the columns are a multiple of 8 for the bit packing
+const TILE_ROWS: usize = 32;
+const TILE_COLS: usize = 256;
+
+/// Split the `n x m` grid into tiles, row-major
+fn tiles(n: usize, m: usize) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut res = Vec::new();
+    for i in (0..n).step_by(TILE_ROWS) {
+        for j in (0..m).step_by(TILE_COLS) {
+            res.push((i..min(i + TILE_ROWS, n), j..min(j + TILE_COLS, m)));
+        }
+    }
+    res
+}
+
+/// Select the packing algorithm: exact search, branching or best-fit (with `branching<=1`).
+/// Sparse item sets use the sparse algorithms, except for the exact search and the checks
+/// against a dense item set (done on count vectors, see `ItemSetStore::check_dense_fallback`).
+pub(crate) fn fits_into<T: Count>(
+    a: SetRef<T>,
+    b: SetRef<T>,
+    branching: usize,
+    exact: bool,
+) -> FitResult {
+    match (a, b) {
+        (SetRef::Dense(a), SetRef::Dense(b)) if exact => fits_into_exact(a, b),
+        (SetRef::Dense(a), SetRef::Dense(b)) => fits_into_branching(a, b, branching),
+        (SetRef::Sparse(a), SetRef::Sparse(b)) if !exact => {
+            fits_into_branching_sparse(a, b, branching)
+        }
+        _ if exact => fits_into_exact(&a.to_dense(), &b.to_dense()),
+        _ => fits_into_branching(&a.to_dense(), &b.to_dense(), branching),
+    }
+}
@@ -1,13 +1,49 @@
+#![allow(clippy::bool_assert_comparison)]
+
+#[allow(unused_imports)]
+use crate::encoding::{decode_sets, encode_sets};
+#[allow(unused_imports)]
+use crate::file_format::{read_sets, write_sets, MappedSets, HEADER_LEN};
+#[allow(unused_imports)]
+use crate::flat_vecs::FlatVecs;
 use crate::packing_bestfit::fits_into_bestfit;
 use crate::packing_branching::fits_into_branching;
+#[cfg(test)]
+use crate::packing_common::trim_upper_bins;
+#[allow(unused_imports)]
+use crate::packing_common::{
+    bounds_may_fit, check_counts, check_counts_range, check_sizes_range, counts_to_sizes, item_sum,
+    sizes_to_counts, try_counts, try_sizes_to_counts, FitResult, RangeError,
+};
+use crate::packing_exact::fits_into_exact;
+#[allow(unused_imports)]
+use crate::packing_min_bins::{
+    best_fit_decreasing, first_fit_decreasing, lower_bound_l1, lower_bound_l2, min_bins,
+    MAX_EXACT_CAPACITY,
+};
+#[allow(unused_imports)]
+use crate::packing_sparse::{
+    bounds_may_fit_sparse, dense_to_sparse, fits_into_bestfit_sparse, fits_into_branching_sparse,
+    sparse_to_dense, try_pairs_to_sparse, try_sizes_to_sparse,
+};
 #[allow(unused_imports)]
-use crate::packing_common::{counts_to_sizes, item_sum, sizes_to_counts, trim_upper_bins};
+use crate::packing_subset::{max_packable, MaxPackable};
+#[allow(unused_imports)]
+use crate::packing_variable::{cheapest_bins, BinType, CheapestBins};
+#[cfg(test)]
+use crate::packing_witness::{fit_report, FitReport};
+#[allow(unused_imports)]
+use crate::packing_witness::{packing_into_branching, packing_into_exact, verify_packing, Packing};
+#[allow(unused_imports)]
+use crate::storage::{
+    FitOptions, ItemSetStore, SetBuf, SetRef, Storage, StoreError, DENSE_MAX_SIZE,
+};
 use crate::C;
 
 #[allow(non_snake_case, dead_code)]
 fn assert_sizes_fit_BF(sa: &[C], sb: &[C], expect: bool) {
     assert_eq!(
-        fits_into_bestfit(&sizes_to_counts(sa), &sizes_to_counts(sb), false),
+        fits_into_bestfit(&sizes_to_counts(sa), &sizes_to_counts(sb), false).fits(),
         expect
     );
 }
@@ -15,43 +51,103 @@ fn assert_sizes_fit_BF(sa: &[C], sb: &[C], expect: bool) {
 #[allow(non_snake_case, dead_code)]
 fn assert_sizes_fit_E(sa: &[C], sb: &[C], branchings: usize, expect: bool) {
     assert_eq!(
-        fits_into_branching(&sizes_to_counts(sa), &sizes_to_counts(sb), branchings),
+        fits_into_branching(&sizes_to_counts(sa), &sizes_to_counts(sb), branchings).fits(),
+        expect
+    );
+}
+
+#[allow(non_snake_case, dead_code)]
+fn assert_sizes_fit_X(sa: &[C], sb: &[C], expect: bool) {
+    assert_eq!(
+        fits_into_exact(&sizes_to_counts(sa), &sizes_to_counts(sb)).fits(),
         expect
     );
 }
 
+/// Naive exhaustive packing of item sizes into bin sizes, for cross-checking
+#[allow(dead_code)]
+fn brute_force_fits(items: &[C], bins: &mut [i32]) -> bool {
+    match items.split_first() {
+        None => true,
+        Some((&it, rest)) => {
+            for i in 0..bins.len() {
+                if bins[i] >= it as i32 {
+                    bins[i] -= it as i32;
+                    let r = brute_force_fits(rest, bins);
+                    bins[i] += it as i32;
+                    if r {
+                        return true;
+                    }
+                }
+            }
+            false
+        }
+    }
+}
+
+/// Deterministic pseudo-random size lists (LCG), `n` items of sizes `1..k`
+#[allow(dead_code)]
+fn pseudo_random_sizes(seed: &mut u64, n: usize, k: u64) -> Vec<C> {
+    (0..n)
+        .map(|_| {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((*seed >> 33) % (k - 1) + 1) as C
+        })
+        .collect()
+}
+
+/// Check that `p` packs exactly the item sizes `sa` into exactly the bin sizes `sb`
+#[allow(dead_code)]
+fn assert_packing_valid(sa: &[C], sb: &[C], p: &Packing) {
+    let mut bins: Vec<usize> = p.iter().map(|(b, _)| *b).collect();
+    let mut items: Vec<usize> = p.iter().flat_map(|(_, its)| its.iter().cloned()).collect();
+    for (b, its) in p {
+        assert!(its.iter().sum::<usize>() <= *b);
+    }
+    bins.sort_unstable();
+    items.sort_unstable();
+    let mut sa: Vec<usize> = sa.iter().map(|x| *x as usize).collect();
+    let mut sb: Vec<usize> = sb.iter().map(|x| *x as usize).collect();
+    sa.sort_unstable();
+    sb.sort_unstable();
+    assert_eq!(items, sa);
+    assert_eq!(bins, sb);
+}
+
 #[test]
 fn test_unit() {
-    assert_eq!(counts_to_sizes(&[]), vec![]);
-    assert_eq!(counts_to_sizes(&[0, 2, 1]), vec![2, 1, 1]);
-    assert_eq!(sizes_to_counts(&[5, 1, 1, 2]), vec![0, 2, 1, 0, 0, 1]);
+    assert_eq!(counts_to_sizes::<C>(&[]), vec![]);
+    assert_eq!(counts_to_sizes::<C>(&[0, 2, 1]), vec![2, 1, 1]);
+    assert_eq!(sizes_to_counts::<C>(&[5, 1, 1, 2]), vec![0, 2, 1, 0, 0, 1]);
     assert_eq!(item_sum(&[0]), 0);
     assert_eq!(item_sum(&[0, 3, 1, 0, 0, 1]), 10);
 }
 
 #[test]
 fn test_trim_upper_bins() {
-    let mut d = vec![0i32, 0, 0, 0, 0, 0];
+    let mut d = vec![0i64, 0, 0, 0, 0, 0];
     assert_eq!(trim_upper_bins(&mut d), true);
     assert_eq!(&d, &[0, 0, 0, 0, 0, 0]);
 
-    let mut d = vec![0i32, 0, 0, -1, 0, 1];
+    let mut d = vec![0i64, 0, 0, -1, 0, 1];
     assert_eq!(trim_upper_bins(&mut d), false);
 
-    let mut d = vec![0i32, 0, 0, 0, 0, 1];
+    let mut d = vec![0i64, 0, 0, 0, 0, 1];
     assert_eq!(trim_upper_bins(&mut d), false);
 
-    let mut d = vec![0i32, 0, 0, 1, 1, -1];
+    let mut d = vec![0i64, 0, 0, 1, 1, -1];
     assert_eq!(trim_upper_bins(&mut d), false);
 
-    let mut d = vec![0i32, 0, 0, 7, 6, -1, -1];
+    let mut d = vec![0i64, 0, 0, 7, 6, -1, -1];
     assert_eq!(trim_upper_bins(&mut d), true);
 
-    let mut d = vec![0i32, 1, 0, 1, 1, 0, -2];
+    let mut d = vec![0i64, 1, 0, 1, 1, 0, -2];
     assert_eq!(trim_upper_bins(&mut d), true);
     assert_eq!(&d, &[0, 1, -1, -1, 0, 0, 0]);
 
-    let mut d = vec![0i32, 0, 0, 1, 0, 0, -1];
+    let mut d = vec![0i64, 0, 0, 1, 0, 0, -1];
     assert_eq!(trim_upper_bins(&mut d), true);
     assert_eq!(&d, &[0, 0, 0, -1, 0, 0, 0]);
 }
@@ -59,21 +155,27 @@ fn test_trim_upper_bins() {
 #[test]
 fn test_fits_into_bestfit() {
     // Direct bestfit
-    assert_eq!(fits_into_bestfit(&[], &[], false), true);
+    assert_eq!(fits_into_bestfit::<C>(&[], &[], false).fits(), true);
+    assert_eq!(
+        fits_into_bestfit::<C>(&[0, 0, 0, 0, 0, 0], &[0, 0, 0, 0, 0, 0], false).fits(),
+        true
+    );
+    assert_eq!(
+        fits_into_bestfit::<C>(&[0, 0, 0, 1], &[0, 0, 0, 0], false).fits(),
+        false
+    );
     assert_eq!(
-        fits_into_bestfit(&[0, 0, 0, 0, 0, 0], &[0, 0, 0, 0, 0, 0], false),
+        fits_into_bestfit::<C>(&[0, 0, 0, 1, 0, 0], &[0, 0, 0, 1, 1, 0], false).fits(),
         true
     );
     assert_eq!(
-        fits_into_bestfit(&[0, 0, 0, 1], &[0, 0, 0, 0], false),
+        fits_into_bestfit::<C>(&[0, 0, 3], &[0, 0, 0, 2], false).fits(),
         false
     );
     assert_eq!(
-        fits_into_bestfit(&[0, 0, 0, 1, 0, 0], &[0, 0, 0, 1, 1, 0], false),
+        fits_into_bestfit::<C>(&[0, 1, 0, 1], &[0, 0, 1, 1], false).fits(),
         true
     );
-    assert_eq!(fits_into_bestfit(&[0, 0, 3], &[0, 0, 0, 2], false), false);
-    assert_eq!(fits_into_bestfit(&[0, 1, 0, 1], &[0, 0, 1, 1], false), true);
 
     // From sizes, no trim_upper
     assert_sizes_fit_BF(&[], &[], true);
@@ -98,6 +200,1188 @@ fn test_fits_into_branching() {
     assert_sizes_fit_E(&[3, 3, 2, 5], &[6, 7], 1, false); // BF fails
     assert_sizes_fit_E(&[3, 3, 2, 5], &[6, 7], 2, true); // BF would fail
     assert_sizes_fit_E(&[3, 3, 2, 5], &[6, 8], 2, true);
-    assert_sizes_fit_E(&[1,2,3,4,5,6,7,8,9,10], &[17,14,11,13], 2, false); // BF would fail, 2 branches are not enough
-    assert_sizes_fit_E(&[1,2,3,4,5,6,7,8,9,10], &[17,14,11,13], 10, true); // BF would fail
-}
\ No newline at end of file
+    assert_sizes_fit_E(
+        &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+        &[17, 14, 11, 13],
+        2,
+        false,
+    ); // BF would fail, 2 branches are not enough
+    assert_sizes_fit_E(
+        &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+        &[17, 14, 11, 13],
+        10,
+        true,
+    ); // BF would fail
+}
+
+#[test]
+fn test_fits_into_exact() {
+    assert_sizes_fit_X(&[], &[], true);
+    assert_sizes_fit_X(&[2, 2, 2], &[3, 3], false);
+    assert_sizes_fit_X(&[1, 2, 3, 4], &[10], true);
+    assert_sizes_fit_X(&[1, 2, 3, 4], &[9], false);
+    assert_sizes_fit_X(&[3, 3, 3, 3], &[4, 4, 4], false);
+    assert_sizes_fit_X(&[3, 3, 2, 5], &[6, 7], true); // BF would fail
+    assert_sizes_fit_X(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], &[17, 14, 11, 13], true); // 2 branches are not enough
+    assert_sizes_fit_X(&[6, 6, 4, 4, 4, 3, 3], &[10, 10, 10], true);
+    assert_sizes_fit_X(&[7, 7, 7, 4, 4, 4], &[10, 10, 13], false); // volume fits
+
+    // Compare with naive search on small instances
+    let mut seed = 42;
+    for _ in 0..500 {
+        let sa = pseudo_random_sizes(&mut seed, 7, 10);
+        let mut sb: Vec<i32> = pseudo_random_sizes(&mut seed, 4, 16)
+            .iter()
+            .map(|x| *x as i32)
+            .collect();
+        let mut sa_sorted = sa.clone();
+        sa_sorted.sort_unstable_by(|x, y| y.cmp(x));
+        let expect = brute_force_fits(&sa_sorted, &mut sb);
+        let sb: Vec<C> = sb.iter().map(|x| *x as C).collect();
+        assert_sizes_fit_X(&sa, &sb, expect);
+        // Branching may only miss packings
+        match fits_into_branching(&sizes_to_counts(&sa), &sizes_to_counts(&sb), 10) {
+            FitResult::Fits => assert!(expect),
+            FitResult::DoesNotFit => assert!(!expect),
+            FitResult::Unknown => {}
+        }
+    }
+}
+
+#[test]
+fn test_deep_search() {
+    // Every item placed is one level of the search, which must not overflow a small thread stack
+    let a: Vec<u32> = vec![0, 0, 20000, 20000];
+    let b: Vec<u32> = vec![0, 0, 0, 0, 0, 20000];
+    let r = std::thread::Builder::new()
+        .stack_size(1 << 20)
+        .spawn(move || {
+            let (sa, sb) = (dense_to_sparse(&a), dense_to_sparse(&b));
+            let packing = packing_into_exact(&a, &b).unwrap();
+            assert!(verify_packing(&a, &b, &packing).is_ok());
+            (
+                fits_into_exact(&a, &b),
+                fits_into_branching(&a, &b, 10),
+                fits_into_branching_sparse(&sa, &sb, 10),
+            )
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(r, (FitResult::Fits, FitResult::Fits, FitResult::Fits));
+}
+
+#[test]
+fn test_fit_result() {
+    let f = |sa: &[C], sb: &[C], branchings| {
+        fits_into_branching(&sizes_to_counts(sa), &sizes_to_counts(sb), branchings)
+    };
+    assert_eq!(f(&[1, 2, 3, 4], &[10], 0), FitResult::Fits);
+    assert_eq!(f(&[2, 2, 2], &[3, 3], 0), FitResult::DoesNotFit); // volume
+    assert_eq!(f(&[5], &[3, 3], 0), FitResult::DoesNotFit); // largest item
+    assert_eq!(f(&[3, 3, 3, 3], &[4, 4, 4], 0), FitResult::DoesNotFit); // volume after forgetting
+    assert_eq!(f(&[3, 3, 2, 5], &[6, 7], 0), FitResult::Unknown); // BF gave up
+    assert_eq!(f(&[3, 3, 2, 5], &[6, 7], 2), FitResult::Fits);
+    assert_eq!(f(&[7, 7, 7, 4, 4, 4], &[10, 10, 13], 0), FitResult::Unknown);
+    assert_eq!(
+        f(&[7, 7, 7, 4, 4, 4], &[10, 10, 13], 1000),
+        FitResult::DoesNotFit
+    );
+    assert_eq!(
+        fits_into_bestfit::<C>(
+            &sizes_to_counts(&[2, 2, 2, 2]),
+            &sizes_to_counts(&[3, 3]),
+            false
+        ),
+        FitResult::DoesNotFit
+    );
+    assert_eq!(
+        fits_into_exact::<C>(
+            &sizes_to_counts(&[7, 7, 7, 4, 4, 4]),
+            &sizes_to_counts(&[10, 10, 13])
+        ),
+        FitResult::DoesNotFit
+    );
+}
+
+#[test]
+fn test_packing_witness() {
+    let p = |sa: &[C], sb: &[C], branchings| {
+        packing_into_branching(&sizes_to_counts(sa), &sizes_to_counts(sb), branchings)
+    };
+    assert_eq!(p(&[], &[], 0), Some(vec![]));
+    assert_eq!(p(&[2, 2, 2], &[3, 3], 0), None);
+    assert_eq!(
+        p(&[1, 2, 3, 4], &[10], 0),
+        Some(vec![(10, vec![4, 3, 2, 1])])
+    );
+    assert_eq!(p(&[3, 3, 2, 5], &[6, 7], 1), None); // BF fails
+    assert_eq!(
+        p(&[3, 3, 2, 5], &[6, 7], 2),
+        Some(vec![(7, vec![5, 2]), (6, vec![3, 3])])
+    );
+    assert_eq!(p(&[4], &[4, 5], 0), Some(vec![(5, vec![]), (4, vec![4])]));
+
+    let mut seed = 7;
+    for _ in 0..500 {
+        let sa = pseudo_random_sizes(&mut seed, 8, 10);
+        let sb = pseudo_random_sizes(&mut seed, 5, 16);
+        let (ca, cb) = (sizes_to_counts(&sa), sizes_to_counts(&sb));
+        for br in [0, 1, 2, 10] {
+            let r = packing_into_branching(&ca, &cb, br);
+            assert_eq!(r.is_some(), fits_into_branching(&ca, &cb, br).fits());
+            if let Some(r) = r {
+                assert_packing_valid(&sa, &sb, &r);
+                assert_eq!(verify_packing(&ca, &cb, &r), Ok(()));
+            }
+        }
+        let r = packing_into_exact(&ca, &cb);
+        assert_eq!(r.is_some(), fits_into_exact(&ca, &cb).fits());
+        if let Some(r) = r {
+            assert_packing_valid(&sa, &sb, &r);
+        }
+    }
+}
+
+#[test]
+fn test_verify_packing() {
+    let v = |sa: &[C], sb: &[C], p: &[(usize, Vec<usize>)]| {
+        verify_packing(&sizes_to_counts(sa), &sizes_to_counts(sb), p).is_ok()
+    };
+    assert_eq!(v(&[], &[], &[]), true);
+    assert_eq!(v(&[], &[3], &[]), true);
+    assert_eq!(v(&[1, 2], &[3, 3], &[(3, vec![2, 1])]), true);
+    assert_eq!(v(&[1, 2], &[3, 3], &[(3, vec![2]), (3, vec![1])]), true);
+    assert_eq!(
+        v(&[1, 2], &[3, 3], &[(3, vec![2]), (3, vec![1]), (3, vec![])]),
+        false
+    ); // bin used 3 times
+    assert_eq!(v(&[1, 2], &[3, 3], &[(3, vec![2])]), false); // item 1 missing
+    assert_eq!(v(&[1, 2], &[3, 3], &[(3, vec![2, 2, 1])]), false); // overflow
+    assert_eq!(v(&[1, 2], &[3, 3], &[(3, vec![2]), (3, vec![1, 1])]), false); // item used twice
+    assert_eq!(v(&[1, 2], &[3, 3], &[(4, vec![2, 1])]), false); // no such bin
+    assert_eq!(v(&[1, 2], &[3, 3], &[(3, vec![2, 1, 0])]), false); // size 0 item
+    assert_eq!(v(&[5], &[], &[]), false);
+    // Sizes that would wrap the volume sum are rejected as unavailable
+    assert_eq!(
+        verify_packing(
+            &sizes_to_counts(&[1 as C]),
+            &sizes_to_counts(&[3 as C]),
+            &[(3, vec![1, 1 << 63, 1 << 63])]
+        ),
+        Err("Item of size 9223372036854775808 in bin 0 is not available".to_string())
+    );
+}
+
+#[test]
+fn test_fit_report() {
+    // As in `fits_into` and `fits_into_sizes` (sizes up to the dense default max_size)
+    let f = |sa: &[usize], sb: &[usize], branching: usize, exact: bool| {
+        let a = try_sizes_to_counts::<u32>(sa, 1 << 20).unwrap();
+        let b = try_sizes_to_counts::<u32>(sb, 1 << 20).unwrap();
+        fit_report(&a, &b, branching, exact)
+    };
+    assert_eq!(
+        f(&[2, 2], &[4], 10, false),
+        FitReport {
+            result: FitResult::Fits,
+            algorithm: "branching",
+            item_volume: 4,
+            bin_volume: 4,
+            bounds_ok: true,
+            packing: Some(vec![(4, vec![2, 2])]),
+        }
+    );
+    // The bounds hold, but only the complete search proves that the items do not fit
+    let r = f(&[3, 3, 2], &[4, 4], 0, true);
+    assert_eq!(
+        (r.result, r.algorithm, r.bounds_ok),
+        (FitResult::DoesNotFit, "exact", true)
+    );
+    assert_eq!((r.item_volume, r.bin_volume, r.packing), (8, 8, None));
+    let r = f(&[3, 3, 2], &[4, 4], 1, false);
+    assert_eq!((r.result.fits(), r.algorithm), (false, "bestfit"));
+    let r = f(&[5], &[4, 4], 0, false);
+    assert_eq!((r.result, r.bounds_ok), (FitResult::DoesNotFit, false));
+    assert!(try_sizes_to_counts::<u32>(&[1_000_000_000_000], 1 << 20)
+        .unwrap_err()
+        .is_overflow());
+}
+
+#[test]
+fn test_bounds_may_fit() {
+    let f = |sa: &[C], sb: &[C]| bounds_may_fit(&sizes_to_counts(sa), &sizes_to_counts(sb));
+    assert_eq!(f(&[], &[]), true);
+    assert_eq!(f(&[], &[5]), true);
+    assert_eq!(f(&[1], &[]), false);
+    assert_eq!(f(&[1, 2, 3, 4], &[10]), true);
+    assert_eq!(f(&[1, 2, 3, 4], &[9]), false); // volume
+    assert_eq!(f(&[5], &[3, 3]), false); // largest item
+    assert_eq!(f(&[4, 4], &[3, 10]), true);
+    assert_eq!(f(&[4, 4, 1], &[3, 3, 7]), false); // volume of items >= 4
+    assert_eq!(
+        f(&[6, 6, 6], &[10, 10, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]),
+        false
+    ); // items > half
+    assert_eq!(f(&[6, 6, 6], &[10, 10, 12]), true);
+    assert_eq!(f(&[4, 4, 4, 4], &[8, 9]), true);
+    assert_eq!(f(&[4, 4, 4, 4, 4], &[8, 9, 3]), false); // at most 2 items of size >= 4 per bin
+
+    // Bounds never refute a possible packing
+    let mut seed = 3;
+    for _ in 0..500 {
+        let mut sa = pseudo_random_sizes(&mut seed, 6, 12);
+        let sb = pseudo_random_sizes(&mut seed, 4, 16);
+        sa.sort_unstable_by(|x, y| y.cmp(x));
+        let mut bins: Vec<i32> = sb.iter().map(|x| *x as i32).collect();
+        if brute_force_fits(&sa, &mut bins) {
+            assert!(bounds_may_fit(&sizes_to_counts(&sa), &sizes_to_counts(&sb)));
+        }
+    }
+}
+
+#[test]
+fn test_wide_counts() {
+    let a = sizes_to_counts::<u16>(&[300, 200, 500, 1, 1]);
+    assert_eq!(a.len(), 501);
+    assert_eq!(a[1], 2);
+    assert_eq!(counts_to_sizes(&a), vec![500, 300, 200, 1, 1]);
+    let b = sizes_to_counts::<u16>(&[1000, 2]);
+    assert_eq!(fits_into_branching(&a, &b, 0), FitResult::Fits);
+    assert_eq!(
+        fits_into_exact(&a, &sizes_to_counts::<u16>(&[800, 201])),
+        FitResult::DoesNotFit
+    );
+
+    // Counts over 255
+    let mut a = vec![0u32; 3];
+    a[1] = 1000;
+    a[2] = 300;
+    assert_eq!(item_sum(&a), 1600);
+    assert_eq!(
+        fits_into_branching(&a, &[0, 0, 0, 0, 400], 0),
+        FitResult::Fits
+    );
+    assert_eq!(
+        fits_into_branching(&a, &[0, 0, 0, 0, 399], 0),
+        FitResult::DoesNotFit
+    );
+}
+
+#[test]
+fn test_size_count_ranges() {
+    assert_eq!(
+        try_sizes_to_counts::<u8>(&[3, 1, 1], 3),
+        Ok(vec![0, 2, 0, 1])
+    );
+    assert_eq!(
+        try_sizes_to_counts::<u8>(&[3, 1, 4], 3),
+        Err(RangeError::SizeTooLarge {
+            index: 2,
+            size: 4,
+            max_size: 3
+        })
+    );
+    let ones = vec![1; 256];
+    assert_eq!(
+        try_sizes_to_counts::<u8>(&ones, 255),
+        Err(RangeError::CountOverflow {
+            size: 1,
+            count: 256,
+            max_count: 255
+        })
+    );
+    assert_eq!(try_sizes_to_counts::<u16>(&ones, 255).unwrap()[1], 256);
+    assert_eq!(try_sizes_to_counts::<u16>(&[300], 1000).unwrap().len(), 301);
+
+    assert_eq!(check_counts_range::<u8>(&[0, 1, 0, 0], 1), Ok(()));
+    assert_eq!(
+        check_counts_range::<u8>(&[0, 1, 1, 0], 1),
+        Err(RangeError::SizeTooLarge {
+            index: 2,
+            size: 2,
+            max_size: 1
+        })
+    );
+}
+
+#[test]
+fn test_input_errors() {
+    assert_eq!(check_sizes_range(&[3, 1], 3), Ok(()));
+    assert_eq!(
+        check_sizes_range(&[3, 0, 1], 3),
+        Err(RangeError::ZeroSize { index: 1 })
+    );
+    assert_eq!(
+        try_sizes_to_counts::<u8>(&[2, 0], 3),
+        Err(RangeError::ZeroSize { index: 1 })
+    );
+    assert_eq!(
+        try_sizes_to_sparse::<u8>(&[0], 3),
+        Err(RangeError::ZeroSize { index: 0 })
+    );
+
+    assert_eq!(try_counts::<u8>(&[], 3), Ok(vec![]));
+    assert_eq!(
+        try_counts::<u8>(&[0, 2, 0, 1, 0, 0], 3),
+        Ok(vec![0, 2, 0, 1, 0, 0])
+    );
+    assert_eq!(
+        try_counts::<u8>(&[1, 2], 3),
+        Err(RangeError::ZeroSizeCount { count: 1 })
+    );
+    assert_eq!(
+        try_counts::<u8>(&[0, 0, 0, 0, 1], 3),
+        Err(RangeError::SizeTooLarge {
+            index: 4,
+            size: 4,
+            max_size: 3
+        })
+    );
+    assert_eq!(
+        try_counts::<u8>(&[0, 1, 256], 3),
+        Err(RangeError::CountOverflow {
+            size: 2,
+            count: 256,
+            max_count: 255
+        })
+    );
+    assert_eq!(check_counts::<u8>(&[0, 2, 0, 1, 0], 3), Ok(()));
+    assert_eq!(
+        check_counts::<u8>(&[2, 2], 3),
+        Err(RangeError::ZeroSizeCount { count: 2 })
+    );
+    assert!(RangeError::CountOverflow {
+        size: 2,
+        count: 256,
+        max_count: 255
+    }
+    .is_overflow());
+    assert!(!RangeError::ZeroSize { index: 1 }.is_overflow());
+    assert_eq!(
+        RangeError::ZeroSize { index: 1 }.to_string(),
+        "Item size 0 at index 1 is not allowed"
+    );
+}
+
+#[test]
+fn test_sparse() {
+    let cs = sizes_to_counts::<C>(&[7, 3, 3, 1]);
+    let sp = dense_to_sparse(&cs);
+    assert_eq!(sp, vec![(1, 1), (3, 2), (7, 1)]);
+    assert_eq!(sparse_to_dense(&sp), cs);
+    assert_eq!(try_sizes_to_sparse::<u8>(&[7, 3, 1, 3], 7), Ok(sp));
+    assert_eq!(
+        try_sizes_to_sparse::<u8>(&[3, 1, 4], 3),
+        Err(RangeError::SizeTooLarge {
+            index: 2,
+            size: 4,
+            max_size: 3
+        })
+    );
+    assert_eq!(
+        try_sizes_to_sparse::<u8>(&[1; 256], 255),
+        Err(RangeError::CountOverflow {
+            size: 1,
+            count: 256,
+            max_count: 255
+        })
+    );
+    // Large sizes only take space per distinct size
+    let big = try_sizes_to_sparse::<u32>(&[1_000_000, 400_000, 600_000], usize::MAX).unwrap();
+    let bins = try_sizes_to_sparse::<u32>(&[1_000_000, 1_000_000], usize::MAX).unwrap();
+    assert_eq!(fits_into_branching_sparse(&big, &bins, 0), FitResult::Fits);
+
+    // Sparse algorithms give the same results as the dense ones
+    let mut seed = 11;
+    for _ in 0..500 {
+        let sa = sizes_to_counts(&pseudo_random_sizes(&mut seed, 7, 20));
+        let sb = sizes_to_counts(&pseudo_random_sizes(&mut seed, 4, 40));
+        let (pa, pb) = (dense_to_sparse(&sa), dense_to_sparse(&sb));
+        assert_eq!(bounds_may_fit_sparse(&pa, &pb), bounds_may_fit(&sa, &sb));
+        for trim_upper in [false, true] {
+            assert_eq!(
+                fits_into_bestfit_sparse(&pa, &pb, trim_upper),
+                fits_into_bestfit(&sa, &sb, trim_upper)
+            );
+        }
+        for branchings in [0, 1, 2, 10] {
+            assert_eq!(
+                fits_into_branching_sparse(&pa, &pb, branchings),
+                fits_into_branching(&sa, &sb, branchings)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_storage_removal() {
+    for sparse in [false, true] {
+        let mut st = Storage::<C>::new(sparse);
+        for i in 1..=6 {
+            st.push(st.prepare(sizes_to_counts(&[i, i])));
+        }
+        let sizes =
+            |st: &Storage<C>| -> Vec<usize> { st.iter().map(|s| s.to_sizes()[0]).collect() };
+        assert_eq!(st.remove(1).to_dense(), sizes_to_counts(&[2, 2]));
+        assert_eq!(sizes(&st), vec![1, 3, 4, 5, 6]);
+        st.retain(&[true, false, true, false, true]);
+        assert_eq!(sizes(&st), vec![1, 4, 6]);
+        assert_eq!(st.get(2).unwrap().to_dense(), sizes_to_counts(&[6, 6]));
+        st.retain(&[false, false, false]);
+        assert_eq!(st.len(), 0);
+        st.push(st.prepare(sizes_to_counts(&[1])));
+        st.clear();
+        assert_eq!(st.len(), 0);
+        assert!(st.get(0).is_none());
+    }
+}
+
+#[test]
+fn test_push_if_not_dominated() {
+    // `y` is dominated by `x` when it fits into `x` (keeping the maximal sets)
+    let dominates =
+        |x: SetRef<C>, y: SetRef<C>| fits_into_branching(&y.to_dense(), &x.to_dense(), 0).fits();
+    for (sparse, par) in [(false, false), (true, true)] {
+        let mut st = Storage::<C>::new(sparse);
+        let mut push = |sizes: &[C]| {
+            st.push_if_not_dominated(st.prepare(sizes_to_counts(sizes)), par, dominates)
+        };
+        assert!(push(&[3, 3]));
+        assert!(push(&[5]));
+        assert!(!push(&[2, 2])); // fits into [3, 3]
+        assert!(!push(&[3, 3])); // equal
+        assert!(push(&[6, 4])); // evicts [3, 3] and [5]
+        assert!(push(&[4, 4, 4])); // incomparable with [6, 4]
+        let mut all: Vec<Vec<usize>> = st.iter().map(|s| s.to_sizes()).collect();
+        all.sort();
+        assert_eq!(all, vec![vec![4, 4, 4], vec![6, 4]]);
+    }
+}
+
+#[test]
+fn test_fit_matrix() {
+    let fits =
+        |x: SetRef<C>, y: SetRef<C>| fits_into_branching(&x.to_dense(), &y.to_dense(), 0).fits();
+    let mut seed = 5;
+    // Over one tile in both directions, with partial tiles and bytes
+    let (mut a, mut b) = (Storage::<C>::new(false), Storage::<C>::new(true));
+    for _ in 0..37 {
+        a.push(a.prepare(sizes_to_counts(&pseudo_random_sizes(&mut seed, 3, 10))));
+    }
+    for _ in 0..261 {
+        b.push(b.prepare(sizes_to_counts(&pseudo_random_sizes(&mut seed, 2, 14))));
+    }
+    let mut expected = Vec::new();
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            if fits(x, y) {
+                expected.push((i, j));
+            }
+        }
+    }
+    assert!(!expected.is_empty() && expected.len() < 37 * 261);
+    for par in [false, true] {
+        assert_eq!(a.fit_matrix_pairs(&b, par, fits), expected);
+        let bits = a.fit_matrix_packed(&b, par, fits);
+        assert_eq!(bits.len(), 37 * 33);
+        let mut unpacked = Vec::new();
+        for i in 0..37 {
+            for j in 0..261 {
+                if bits[i * 33 + j / 8] & (0x80 >> (j % 8)) != 0 {
+                    unpacked.push((i, j));
+                }
+            }
+        }
+        assert_eq!(unpacked, expected);
+    }
+    assert!(a
+        .fit_matrix_packed(&Storage::new(false), true, fits)
+        .is_empty());
+}
+
+#[test]
+fn test_encoding() {
+    let all = |st: &Storage<u16>| -> Vec<Vec<u16>> { st.iter().map(|s| s.to_dense()).collect() };
+    for sparse in [false, true] {
+        let mut st = Storage::<u16>::new(sparse);
+        for cs in [
+            vec![0, 1, 0, 300],
+            vec![],
+            vec![0, 0, 2, 0, 0],
+            vec![0; 200],
+        ] {
+            st.push(st.prepare(cs));
+        }
+        st.push(st.prepare(sizes_to_counts(&[1000, 1000, 1])));
+        let data = encode_sets(&st);
+        let st2 = decode_sets::<u16>(&data, sparse, 1000).unwrap();
+        if sparse {
+            // Trailing zeros are not kept in the sparse storage
+            assert_eq!(st2.get(2).unwrap().to_dense(), vec![0, 0, 2]);
+        } else {
+            assert_eq!(all(&st2), all(&st));
+        }
+        assert_eq!(
+            encode_sets(&st2),
+            encode_sets(&decode_sets::<u16>(&data, sparse, 1000).unwrap())
+        );
+        // Dense and sparse encodings are interchangeable
+        assert_eq!(
+            all(&decode_sets::<u16>(&data, !sparse, 1000).unwrap())[4],
+            sizes_to_counts(&[1000, 1000, 1])
+        );
+        assert!(decode_sets::<u16>(&data, sparse, 999).is_err());
+        assert!(decode_sets::<u8>(&data, sparse, 1000).is_err());
+        assert!(decode_sets::<u16>(&data[..data.len() - 1], sparse, 1000).is_err());
+        let mut longer = data.clone();
+        longer.push(0);
+        assert!(decode_sets::<u16>(&longer, sparse, 1000).is_err());
+        // One empty item set claiming a length of 2**63-1, rejected without allocating it
+        let huge = [1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0];
+        assert!(decode_sets::<u16>(&huge, sparse, 1000).is_err());
+        assert!(decode_sets::<u16>(&[1, 0xe9, 0x07, 0], sparse, 1000).is_ok()); // length 1001
+    }
+}
+
+#[test]
+fn test_file_format() {
+    let mut st = Storage::<u16>::new(false);
+    for cs in [vec![0, 1, 0, 300], vec![], vec![0, 0, 2, 0, 0]] {
+        st.push(st.prepare(cs));
+    }
+    let mut data = Vec::new();
+    write_sets(&mut data, &st, 100).unwrap();
+    // Header, 4 offsets, 9 counts
+    assert_eq!(data.len(), HEADER_LEN + 4 * 8 + 9 * 2);
+    for sparse in [false, true] {
+        let (st2, max_size) = read_sets::<u16>(&data, sparse).unwrap();
+        assert_eq!(max_size, 100);
+        assert_eq!(st2.len(), 3);
+        assert_eq!(st2.get(0).unwrap().to_dense(), vec![0, 1, 0, 300]);
+        assert_eq!(st2.get(1).unwrap().dense_len(), 0);
+        assert_eq!(st2.get(2).unwrap().to_sizes(), vec![2, 2]);
+    }
+    let mut empty = Vec::new();
+    write_sets(&mut empty, &Storage::<u8>::new(true), 7).unwrap();
+    assert_eq!(read_sets::<u8>(&empty, false).unwrap().0.len(), 0);
+
+    // Wrong count type, truncated, trailing bytes
+    assert!(read_sets::<u8>(&data, false).is_err());
+    assert!(read_sets::<u32>(&data, false).is_err());
+    assert!(read_sets::<u16>(&data[..data.len() - 1], false).is_err());
+    assert!(read_sets::<u16>(&data[..HEADER_LEN - 1], false).is_err());
+    let mut longer = data.clone();
+    longer.push(0);
+    assert!(read_sets::<u16>(&longer, false).is_err());
+    // Any single corrupted byte is detected (by a checksum or the magic)
+    for i in 0..data.len() {
+        let mut bad = data.clone();
+        bad[i] ^= 0x10;
+        assert!(read_sets::<u16>(&bad, false).is_err(), "byte {}", i);
+    }
+    // Unsupported version, even with a valid header checksum
+    let mut bad = data.clone();
+    bad[8] = 2;
+    let crc = crc32fast::hash(&bad[..HEADER_LEN - 4]);
+    bad[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    assert!(read_sets::<u16>(&bad, false)
+        .unwrap_err()
+        .contains("version 2"));
+    // Counts are validated against max_size
+    let mut data = Vec::new();
+    write_sets(&mut data, &st, 2).unwrap();
+    assert!(read_sets::<u16>(&data, false).is_err());
+}
+
+#[test]
+fn test_mapped() {
+    let map = |data: &[u8]| {
+        let mut m = memmap2::MmapMut::map_anon(data.len()).unwrap();
+        m.copy_from_slice(data);
+        m.make_read_only().unwrap()
+    };
+    let mut seed = 7;
+    let mut st = Storage::<u8>::new(false);
+    for i in 0..50 {
+        let n = i % 6;
+        st.push(st.prepare(sizes_to_counts(&pseudo_random_sizes(&mut seed, n, 20))));
+    }
+    let mut data = Vec::new();
+    write_sets(&mut data, &st, 1000).unwrap();
+    for verify in [true, false] {
+        let (ms, max_size) = MappedSets::<u8>::new(map(&data), verify).unwrap();
+        assert_eq!(max_size, 1000);
+        let mapped = Storage::Mapped(ms);
+        assert_eq!(mapped.len(), 50);
+        assert!(mapped.get(50).is_none());
+        for (a, b) in mapped.iter().zip(st.iter()) {
+            assert_eq!(a.to_dense(), b.to_dense());
+        }
+        let fits = |a: SetRef<u8>, b: SetRef<u8>| match (a, b) {
+            (SetRef::Dense(a), SetRef::Dense(b)) => fits_into_branching(a, b, 10).fits(),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            mapped.fit_matrix_pairs(&mapped, true, fits),
+            st.fit_matrix_pairs(&st, false, fits)
+        );
+        assert!(matches!(mapped.prepare(vec![0, 1]), SetBuf::Dense(_)));
+    }
+    assert!(MappedSets::<u16>::new(map(&data), false).is_err());
+    // Without verification, only the header and offsets are checked
+    let mut bad = data.clone();
+    *bad.last_mut().unwrap() ^= 1;
+    assert!(MappedSets::<u8>::new(map(&bad), true).is_err());
+    assert!(MappedSets::<u8>::new(map(&bad), false).is_ok());
+    // ... and the counts of size 0, which the algorithms rely on
+    let mut bad = data.clone();
+    let counts_start = data.len() - crate::file_format::offset(&data, 50);
+    bad[counts_start + crate::file_format::offset(&data, 7)] = 1;
+    assert!(MappedSets::<u8>::new(map(&bad), false)
+        .unwrap_err()
+        .contains("Item set 7"));
+    let mut bad = data.clone();
+    bad[HEADER_LEN + 8..HEADER_LEN + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(MappedSets::<u8>::new(map(&bad), false).is_err());
+}
+
+#[test]
+fn test_flat_vecs() {
+    let mut fv = FlatVecs::<u8>::new();
+    for v in [&[1, 2][..], &[], &[3], &[4, 5, 6], &[]] {
+        fv.push(v);
+    }
+    let all = |fv: &FlatVecs<u8>| -> Vec<Vec<u8>> {
+        (0..fv.len()).map(|i| fv.get(i).unwrap().to_vec()).collect()
+    };
+    assert_eq!(fv.len(), 5);
+    assert!(fv.get(5).is_none());
+    assert_eq!(fv.remove(2), vec![3]);
+    assert_eq!(all(&fv), vec![vec![1, 2], vec![], vec![4, 5, 6], vec![]]);
+    fv.retain(&[false, true, true, false]);
+    assert_eq!(all(&fv), vec![vec![], vec![4, 5, 6]]);
+    fv.push(&[7]);
+    assert_eq!(fv.remove(0), vec![]);
+    assert_eq!(all(&fv), vec![vec![4, 5, 6], vec![7]]);
+    // One buffer of counts and one offset per vector (plus one)
+    let mut fv = FlatVecs::<u8>::new();
+    for _ in 0..1000 {
+        fv.push(&[1; 40]);
+    }
+    fv.retain(&vec![true; 1000]);
+    assert!(fv.heap_bytes() <= 2 * (1000 * 40 + 1001 * 8));
+    fv.clear();
+    assert_eq!(fv.len(), 0);
+}
+
+#[test]
+fn test_item_set_store() {
+    let opts = FitOptions::default();
+    for sparse in [false, true] {
+        let mut st = ItemSetStore::<u16>::new(1000, sparse);
+        st.push_sizes(&[3, 3]).unwrap();
+        st.push_sizes(&[5]).unwrap();
+        st.push_counts(&sizes_to_counts(&[1000, 2])).unwrap();
+        assert!(st.push_sizes(&[1001]).is_err());
+        assert!(st.push_counts(&[1, 0]).is_err());
+        assert_eq!(
+            st.push_counts_iter([1, 0]),
+            Err(StoreError::Range(RangeError::ZeroSizeCount { count: 1 }))
+        );
+        assert_eq!(
+            st.push_counts_iter(std::iter::repeat_n(0, 1001).chain([1])),
+            Err(StoreError::Range(RangeError::SizeTooLarge {
+                index: 1001,
+                size: 1001,
+                max_size: 1000
+            }))
+        );
+        assert_eq!(st.len(), 3);
+        assert_eq!(st.is_sparse(), sparse);
+        assert_eq!(st.get(0).unwrap().to_sizes(), vec![3, 3]);
+
+        let bins = sizes_to_counts::<u16>(&[6]);
+        assert!(st.any_fit_into_given(&bins, opts).unwrap());
+        assert!(!st.all_fit_into_given(&bins, opts).unwrap());
+        assert_eq!(st.how_many_fit_into_given(&bins, opts).unwrap(), 2);
+        assert_eq!(st.which_fit_into_given(&bins, opts).unwrap(), vec![0, 1]);
+        assert_eq!(st.first_fit_into_given(&bins, opts).unwrap(), Some(0));
+        assert_eq!(
+            st.packing_into_given(0, &bins, opts).unwrap(),
+            Some(vec![(6, vec![3, 3])])
+        );
+        assert_eq!(st.packing_into_given(2, &bins, opts).unwrap(), None);
+        let par = FitOptions {
+            par: true,
+            exact: true,
+            ..opts
+        };
+        assert_eq!(st.which_fit_into_given(&bins, par).unwrap(), vec![0, 1]);
+        let items = sizes_to_counts::<u16>(&[2]);
+        assert_eq!(
+            st.given_fits_into_which(&items, opts).unwrap(),
+            vec![0, 1, 2]
+        );
+        assert!(st.given_fits_into_all(&items, opts).unwrap());
+        assert_eq!(st.classify_given_fits_into(&items, par).unwrap(), (3, 0, 0));
+        assert_eq!(
+            st.fit_matrix(&st, opts).unwrap(),
+            vec![(0, 0), (0, 2), (1, 1), (1, 2), (2, 2)]
+        );
+        assert_eq!(
+            st.fit_matrix_packed(&st, opts).unwrap(),
+            vec![0xa0, 0x60, 0x20]
+        );
+
+        let st2 = ItemSetStore::<u16>::decode(&st.encode(), sparse, 1000).unwrap();
+        assert_eq!(st2.fit_matrix(&st, opts), st.fit_matrix(&st, opts));
+        // Given as (size, count) pairs, in any order
+        let pairs = SetRef::Sparse(&[(3, 1), (1, 1), (3, 1)]);
+        assert_eq!(
+            st.which_fit_into_given(pairs, opts).unwrap(),
+            st.which_fit_into_given(&sizes_to_counts::<u16>(&[3, 3, 1]), opts)
+                .unwrap()
+        );
+        assert_eq!(
+            st.any_fit_into_given(SetRef::Sparse(&[(1001, 1)]), opts),
+            Err(RangeError::SizeTooLarge {
+                index: 0,
+                size: 1001,
+                max_size: 1000
+            })
+        );
+        assert!(ItemSetStore::<u16>::decode(&st.encode(), sparse, 999).is_err());
+
+        assert!(!st.push_if_not_dominated(&items, true, opts).unwrap());
+        assert!(st
+            .push_if_not_dominated(&sizes_to_counts(&[1000, 5]), true, opts)
+            .unwrap());
+        // Evicts all the other sets
+        assert_eq!(st.len(), 1);
+        st.push_sizes(&[1]).unwrap();
+        st.push_sizes(&[2]).unwrap();
+        assert_eq!(st.remove(0).unwrap(), sizes_to_counts(&[1000, 5]));
+        st.retain(&[false, true]).unwrap();
+        assert_eq!(st.get(0).unwrap().to_sizes(), vec![2]);
+
+        // Mapped item sets are read-only
+        let path = std::env::temp_dir().join(format!("binpack_store_{}.bin", std::process::id()));
+        st.save(&path).unwrap();
+        let mut mapped = ItemSetStore::<u16>::open(&path, false).unwrap();
+        // Saving over an open file replaces it without touching the mapping
+        ItemSetStore::<u16>::new(1000, sparse).save(&path).unwrap();
+        mapped.save(&path).unwrap();
+        assert_eq!(mapped.get(0).unwrap().to_sizes(), vec![2]);
+        assert_eq!(ItemSetStore::<u16>::load(&path, sparse).unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mapped.push_sizes(&[1]), Err(StoreError::ReadOnly));
+        assert_eq!(mapped.push_counts(&[0, 1]), Err(StoreError::ReadOnly));
+        assert_eq!(mapped.push_counts_iter([0, 1]), Err(StoreError::ReadOnly));
+        assert_eq!(mapped.remove(0), Err(StoreError::ReadOnly));
+        assert_eq!(mapped.retain(&[true]), Err(StoreError::ReadOnly));
+        assert_eq!(mapped.clear(), Err(StoreError::ReadOnly));
+        assert_eq!(
+            mapped.push_if_not_dominated(&items, true, opts),
+            Err(StoreError::ReadOnly)
+        );
+        assert_eq!(mapped.get(0).unwrap().to_sizes(), vec![2]);
+
+        st.push_counts_iter([0, 0, 1, 0, 0]).unwrap();
+        assert_eq!(st.get(st.len() - 1).unwrap().to_sizes(), vec![2]);
+        st.clear().unwrap();
+        assert!(st.is_empty());
+    }
+}
+
+#[test]
+fn test_item_set_store_batch() {
+    let mut seed = 7;
+    for sparse in [false, true] {
+        let mut st = ItemSetStore::<C>::new(255, sparse);
+        let mut given = ItemSetStore::<C>::new(255, sparse);
+        for i in 0..30 {
+            st.push_counts(&sizes_to_counts(&pseudo_random_sizes(
+                &mut seed,
+                1 + i % 5,
+                10,
+            )))
+            .unwrap();
+        }
+        for i in 0..20 {
+            given
+                .push_counts(&sizes_to_counts(&pseudo_random_sizes(
+                    &mut seed,
+                    1 + i % 4,
+                    16,
+                )))
+                .unwrap();
+        }
+        for (branching, exact, par) in [(0, false, false), (3, false, true), (0, true, true)] {
+            let opts = FitOptions {
+                branching,
+                exact,
+                par,
+            };
+            let single =
+                |f: &dyn Fn(SetRef<C>) -> usize| -> Vec<usize> { given.iter().map(f).collect() };
+            let b = |v: Vec<bool>| -> Vec<usize> { v.into_iter().map(usize::from).collect() };
+            assert_eq!(
+                b(st.any_fit_into_each(&given, opts).unwrap()),
+                single(&|gc| st.any_fit_into_given(gc, opts).unwrap() as usize)
+            );
+            assert_eq!(
+                b(st.each_fits_into_any(&given, opts).unwrap()),
+                single(&|gc| st.given_fits_into_any(gc, opts).unwrap() as usize)
+            );
+            assert_eq!(
+                st.how_many_fit_into_each(&given, opts).unwrap(),
+                single(&|gc| st.how_many_fit_into_given(gc, opts).unwrap())
+            );
+            assert_eq!(
+                st.each_fits_into_how_many(&given, opts).unwrap(),
+                single(&|gc| st.given_fits_into_how_many(gc, opts).unwrap())
+            );
+        }
+        // Not a trivial relation
+        let counts = st
+            .how_many_fit_into_each(&given, FitOptions::default())
+            .unwrap();
+        assert!(counts.iter().any(|c| *c > 0 && *c < 30));
+        assert!(st
+            .any_fit_into_each(&ItemSetStore::new(255, sparse), FitOptions::default())
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[test]
+fn test_item_set_store_dense_cap() {
+    assert_eq!(
+        try_pairs_to_sparse::<u8>(&[(5, 200), (2, 0), (5, 55), (1, 1)], 10),
+        Ok(vec![(1, 1), (5, 255)])
+    );
+    assert_eq!(
+        try_pairs_to_sparse::<u8>(&[(5, 200), (5, 56)], 10),
+        Err(RangeError::CountOverflow {
+            size: 5,
+            count: 256,
+            max_count: 255
+        })
+    );
+    assert_eq!(
+        try_pairs_to_sparse::<u8>(&[(0, 1)], 10),
+        Err(RangeError::ZeroSizeCount { count: 1 })
+    );
+
+    let huge = DENSE_MAX_SIZE + 1;
+    let too_large = RangeError::SizeTooLarge {
+        index: huge,
+        size: huge,
+        max_size: DENSE_MAX_SIZE,
+    };
+    let mut st = ItemSetStore::<u32>::new(u32::MAX as usize, true);
+    st.push_sizes(&[huge, 2]).unwrap();
+    let bins = SetRef::Sparse(&[(huge as u32, 1), (3, 1)]);
+    let opts = FitOptions::default();
+    let exact = FitOptions {
+        exact: true,
+        ..opts
+    };
+    // The sparse algorithms never convert to count vectors
+    assert_eq!(st.which_fit_into_given(bins, opts), Ok(vec![0]));
+    assert_eq!(st.fit_matrix(&st, opts), Ok(vec![(0, 0)]));
+    // The dense ones fail instead of allocating
+    assert_eq!(st.which_fit_into_given(bins, exact), Err(too_large.clone()));
+    assert_eq!(
+        st.which_fit_into_given(SetRef::Sparse(&[(3, 1)]), exact),
+        Err(too_large.clone())
+    );
+    assert_eq!(st.fit_matrix(&st, exact), Err(too_large.clone()));
+    let dense = ItemSetStore::<u32>::new(10, false);
+    assert_eq!(dense.fit_matrix(&st, opts), Err(too_large.clone()));
+    assert_eq!(
+        st.max_packable_volume_into_given(bins, opts),
+        Err(too_large.clone())
+    );
+    assert_eq!(
+        st.packing_into_given(0, SetRef::Sparse(&[(3, 1)]), opts),
+        Err(too_large.clone())
+    );
+    assert_eq!(
+        st.push_if_not_dominated(bins, true, exact),
+        Err(StoreError::Range(too_large.clone()))
+    );
+    let path = std::env::temp_dir().join(format!("binpack_cap_{}.bin", std::process::id()));
+    let e = st.save(&path).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_min_bins() {
+    let mb = |sa: &[C], w, exact| min_bins(&sizes_to_counts(sa), w, exact).unwrap();
+    assert_eq!(mb(&[], 10, true).bins, 0);
+    assert_eq!(mb(&[10, 10, 10], 10, true).bins, 3);
+    assert_eq!(
+        mb(&[1, 2, 3, 4], 10, true).packing,
+        vec![(10, vec![4, 3, 2, 1])]
+    );
+    // FFD and BFD use 4 bins, the optimum is (5,5), (4,3,3), (4,3,3)
+    let r = mb(&[5, 5, 4, 4, 3, 3, 3, 3], 10, false);
+    assert_eq!((r.bins, r.ffd, r.bfd, r.lower_bound), (4, 4, 4, 3));
+    assert_eq!(r.is_optimal(), false);
+    let r = mb(&[5, 5, 4, 4, 3, 3, 3, 3], 10, true);
+    assert_eq!((r.bins, r.lower_bound, r.is_optimal()), (3, 3, true));
+    // The same scaled over MAX_EXACT_CAPACITY: the exact search is skipped
+    let k = 1 << 18;
+    assert!(10 * k > MAX_EXACT_CAPACITY);
+    let sizes: Vec<usize> = [5, 5, 4, 4, 3, 3, 3, 3].iter().map(|s| s * k).collect();
+    let r = min_bins(
+        &try_sizes_to_counts::<C>(&sizes, usize::MAX).unwrap(),
+        10 * k,
+        true,
+    )
+    .unwrap();
+    assert_eq!((r.bins, r.lower_bound, r.is_optimal()), (4, 3, false));
+    // L2 counts the items over half of the capacity
+    assert_eq!(lower_bound_l1(&sizes_to_counts::<C>(&[6, 6, 6]), 10), 2);
+    assert_eq!(lower_bound_l2(&sizes_to_counts::<C>(&[6, 6, 6]), 10), 3);
+    assert_eq!(
+        min_bins(&sizes_to_counts::<C>(&[3, 11]), 10, true),
+        Err(RangeError::SizeTooLarge {
+            index: 11,
+            size: 11,
+            max_size: 10
+        })
+    );
+
+    let mut seed = 11;
+    for _ in 0..300 {
+        let mut sa = pseudo_random_sizes(&mut seed, 9, 12);
+        sa.sort_unstable_by(|x, y| y.cmp(x));
+        let ca = sizes_to_counts(&sa);
+        let opt = (0..)
+            .find(|&k| brute_force_fits(&sa, &mut vec![11; k]))
+            .unwrap();
+        let r = min_bins(&ca, 11, true).unwrap();
+        assert_eq!(r.bins, opt);
+        assert!(r.is_optimal());
+        assert!(r.l1 <= r.l2 && r.l2 <= opt && opt <= r.ffd.min(r.bfd));
+        assert_eq!(r.ffd, first_fit_decreasing(&ca, 11).len());
+        assert_eq!(r.bfd, best_fit_decreasing(&ca, 11).len());
+        assert_packing_valid(&sa, &vec![11; opt], &r.packing);
+        let h = min_bins(&ca, 11, false).unwrap();
+        assert_eq!(h.bins, r.ffd.min(r.bfd));
+        assert_packing_valid(&sa, &vec![11; h.bins], &h.packing);
+    }
+}
+
+#[test]
+fn test_cheapest_bins() {
+    let bt = |size, cost, available| BinType {
+        size,
+        cost,
+        available,
+    };
+    let cb = |sa: &[C], types: &[BinType], exact| {
+        cheapest_bins(&sizes_to_counts(sa), types, 255, 10, exact).unwrap()
+    };
+    let types = [bt(10, 10, None), bt(6, 5, None), bt(3, 2, None)];
+    let r = cb(&[], &types, true).unwrap();
+    assert_eq!((r.counts, r.cost, r.optimal), (vec![0, 0, 0], 0, true));
+    let r = cb(&[6, 3, 3], &types, true).unwrap();
+    assert_eq!((r.counts, r.cost), (vec![0, 1, 2], 9));
+    // The large bin is cheaper per size and holds all the items, until small bins are cheaper
+    let r = cb(&[3, 3], &[bt(10, 4, None), bt(3, 3, None)], false).unwrap();
+    assert_eq!((r.counts, r.cost, r.optimal), (vec![1, 0], 4, false));
+    let r = cb(&[3, 3, 3], &[bt(10, 4, None), bt(3, 1, None)], false).unwrap();
+    assert_eq!((r.counts, r.cost), (vec![0, 3], 3));
+    assert_eq!(r.packing, vec![(3, vec![3]), (3, vec![3]), (3, vec![3])]);
+    // Limited supply
+    let r = cb(&[6, 6], &[bt(6, 1, Some(1)), bt(12, 5, Some(1))], true).unwrap();
+    assert_eq!((r.counts, r.cost), (vec![0, 1], 5));
+    assert_eq!(
+        cb(&[6, 6], &[bt(6, 1, Some(1)), bt(8, 5, None)], true).map(|r| r.cost),
+        Some(6)
+    );
+    assert_eq!(
+        cb(&[6, 6], &[bt(6, 1, Some(1)), bt(5, 5, None)], true),
+        None
+    );
+    assert_eq!(
+        cb(&[6, 6], &[bt(6, 1, Some(1)), bt(5, 5, None)], false),
+        None
+    );
+    assert_eq!(cb(&[6], &[], false), None);
+    assert_eq!(cb(&[], &[], true).map(|r| r.cost), Some(0));
+    // The large item fits into no available bin, decided before any search
+    let mut sa = vec![3; 40];
+    sa.push(9);
+    let types = [bt(8, 1, None), bt(4, 1, None), bt(10, 1, Some(0))];
+    assert_eq!(cb(&sa, &types, true), None);
+    // The costs saturate
+    let r = cb(&[6, 6], &[bt(6, 1 << 63, None)], true).unwrap();
+    assert_eq!((r.counts, r.cost), (vec![2], u64::MAX));
+    for exact in [false, true] {
+        let r = cb(
+            &[6, 6],
+            &[bt(6, 1 << 63, None), bt(12, 1 << 62, Some(1))],
+            exact,
+        )
+        .unwrap();
+        assert_eq!((r.counts, r.cost), (vec![0, 1], 1 << 62));
+    }
+    assert_eq!(
+        cheapest_bins(
+            &sizes_to_counts::<C>(&[3]),
+            &[bt(3, 1, None), bt(usize::MAX, 1, None)],
+            255,
+            0,
+            false
+        ),
+        Err(RangeError::SizeTooLarge {
+            index: 1,
+            size: usize::MAX,
+            max_size: 255
+        })
+    );
+
+    let mut seed = 5;
+    for _ in 0..200 {
+        let sa = pseudo_random_sizes(&mut seed, 6, 10);
+        let raw = pseudo_random_sizes(&mut seed, 9, 14);
+        let types: Vec<BinType> = raw
+            .chunks(3)
+            .map(|c| {
+                let available = (c[2] % 3 != 0).then_some(c[2] as usize % 4);
+                bt(c[0] as usize, c[1] as u64, available)
+            })
+            .collect();
+        // Brute force over up to 6 bins of every type
+        let mut best: Option<u64> = None;
+        for n in 0..7 * 7 * 7 {
+            let counts = [n % 7, n / 7 % 7, n / 49];
+            if counts
+                .iter()
+                .zip(&types)
+                .any(|(c, t)| *c > t.available.unwrap_or(6))
+            {
+                continue;
+            }
+            let mut bins: Vec<i32> = Vec::new();
+            for (c, t) in counts.iter().zip(&types) {
+                bins.extend(std::iter::repeat_n(t.size as i32, *c));
+            }
+            let cost = counts
+                .iter()
+                .zip(&types)
+                .map(|(c, t)| *c as u64 * t.cost)
+                .sum();
+            let mut sorted = sa.clone();
+            sorted.sort_unstable_by(|x, y| y.cmp(x));
+            if best.is_none_or(|b| cost < b) && brute_force_fits(&sorted, &mut bins) {
+                best = Some(cost);
+            }
+        }
+        let check = |r: &CheapestBins| {
+            let mut sb = Vec::new();
+            for (c, t) in r.counts.iter().zip(&types) {
+                sb.extend(std::iter::repeat_n(t.size as C, *c));
+            }
+            assert_packing_valid(&sa, &sb, &r.packing);
+            assert_eq!(
+                r.cost,
+                r.counts
+                    .iter()
+                    .zip(&types)
+                    .map(|(c, t)| *c as u64 * t.cost)
+                    .sum::<u64>()
+            );
+        };
+        let r = cb(&sa, &types, true);
+        assert_eq!(r.as_ref().map(|r| r.cost), best);
+        r.iter().for_each(check);
+        if let Some(h) = cb(&sa, &types, false) {
+            assert!(h.cost >= best.unwrap());
+            check(&h);
+        }
+    }
+}
+
+#[test]
+fn test_max_packable() {
+    let mp = |sa: &[C], sb: &[C], exact| {
+        max_packable(&sizes_to_counts(sa), &sizes_to_counts(sb), 10, exact)
+    };
+    let r = mp(&[], &[5], false);
+    assert_eq!((r.volume, r.optimal), (0, true));
+    let r = mp(&[2, 3], &[5], false);
+    assert_eq!((r.volume, r.items, r.optimal), (5, vec![0, 0, 1, 1], true));
+    assert_eq!(mp(&[6, 7], &[5], true).volume, 0);
+    assert_eq!(mp(&[4, 4, 3, 3], &[7], true).volume, 7);
+    // Best-fit takes the 4 and the 3 can't be added, the optimum is 3+3
+    let r = mp(&[4, 3, 3], &[6], false);
+    assert_eq!((r.volume, r.optimal), (4, false));
+    let r = mp(&[4, 3, 3], &[6], true);
+    assert_eq!(
+        (r.volume, r.items, r.optimal),
+        (6, vec![0, 0, 0, 2, 0], true)
+    );
+
+    let mut seed = 3;
+    for _ in 0..300 {
+        let sa = pseudo_random_sizes(&mut seed, 7, 10);
+        let sb = pseudo_random_sizes(&mut seed, 3, 12);
+        let mut best = 0;
+        for mask in 0..1u32 << sa.len() {
+            let mut sub: Vec<C> = (0..sa.len())
+                .filter(|i| mask >> i & 1 == 1)
+                .map(|i| sa[i])
+                .collect();
+            sub.sort_unstable_by(|x, y| y.cmp(x));
+            let vol = sub.iter().map(|x| *x as u64).sum();
+            let mut bins: Vec<i32> = sb.iter().map(|x| *x as i32).collect();
+            if vol > best && brute_force_fits(&sub, &mut bins) {
+                best = vol;
+            }
+        }
+        let (ca, cb) = (sizes_to_counts(&sa), sizes_to_counts(&sb));
+        for exact in [false, true] {
+            let r = max_packable(&ca, &cb, 2, exact);
+            assert_eq!(r.volume, item_sum(&r.items) as u64);
+            assert!(r.items.iter().zip(&ca).all(|(x, y)| x <= y));
+            assert!(fits_into_exact(&r.items, &cb).fits());
+            if exact || r.optimal {
+                assert_eq!(r.volume, best);
+            } else {
+                assert!(r.volume <= best);
+            }
+        }
+    }
+
+    let mut store = ItemSetStore::<C>::new(20, false);
+    store.push_sizes(&[4, 3, 3]).unwrap();
+    store.push_sizes(&[2, 2]).unwrap();
+    store.push_sizes(&[9]).unwrap();
+    let opts = FitOptions {
+        exact: true,
+        ..Default::default()
+    };
+    let bins = sizes_to_counts::<C>(&[6]);
+    assert_eq!(
+        store.max_packable_volume_into_given(&bins, opts).unwrap(),
+        vec![6, 4, 0]
+    );
+    let opts = FitOptions {
+        par: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        store.max_packable_volume_into_given(&bins, opts).unwrap(),
+        vec![4, 4, 0]
+    );
+    assert!(store
+        .max_packable_volume_into_given(&sizes_to_counts(&[30]), opts)
+        .is_err());
+}
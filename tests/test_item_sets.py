@@ -0,0 +1,42 @@
+"""Tests of the Python API, run with `python -m unittest discover tests` after `maturin develop`"""
+import unittest
+
+import binpack_pyo3 as bp
+
+try:
+    import numpy as np
+except ImportError:
+    np = None
+
+
+class RetainTest(unittest.TestCase):
+    def sets(self):
+        s = bp.ItemSets()
+        for sizes in [[1], [2], [3], [4]]:
+            s.push_sizes(sizes)
+        return s
+
+    def test_retain_truth_values(self):
+        masks = [[True, False, True, False], (1, 0, 1, 0), iter([1, 0, 2, 0]), bytes([1, 0, 1, 0])]
+        for mask in masks:
+            s = self.sets()
+            s.retain(mask)
+            self.assertEqual(s.all_sizes(), [[1], [3]])
+
+    @unittest.skipIf(np is None, "needs NumPy")
+    def test_retain_numpy(self):
+        s = self.sets()
+        s.retain(np.array([1, 2, 3, 4]) % 2 == 0)
+        self.assertEqual(s.all_sizes(), [[2], [4]])
+
+    def test_retain_invalid(self):
+        s = self.sets()
+        with self.assertRaises(ValueError):
+            s.retain([True])
+        with self.assertRaises(TypeError):
+            s.retain(1)
+        self.assertEqual(len(s), 4)
+
+
+if __name__ == "__main__":
+    unittest.main()